@@ -1,33 +1,100 @@
+//! A transport that doesn't use the network at all.
+//!
+//! Requests are files dropped into a spool directory, e.g.
+//! `printf 'GET / HTTP/1.1\r\n\r\n' > spool/hello.req`, and each response is written next to its
+//! request, e.g. `spool/hello.res`.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tracing::{debug, info};
 use yars::{
     http::{HttpRequest, HttpResponse},
     protocol::HttpProtocol,
     transport::{Transport, TransportResult},
-    Result, YarsServer,
+    Result, TransportError, YarsServer,
 };
 
-struct TestTransport;
+const REQUEST_EXTENSION: &str = "req";
+const PROCESSING_EXTENSION: &str = "processing";
+const RESPONSE_EXTENSION: &str = "res";
+
+#[derive(Default)]
+struct SpoolTransport {
+    dir: Option<PathBuf>,
+}
+
+/// A request file being processed
+struct SpoolRequest {
+    path: PathBuf,
+    /// The file is read all at once, after which the request has ended
+    read: bool,
+}
+
+impl SpoolTransport {
+    fn dir(&self) -> TransportResult<&PathBuf> {
+        self.dir
+            .as_ref()
+            .ok_or(TransportError::Generic("Spool directory not bound".into()))
+    }
+
+    /// Claims the next request in the spool directory, if there is one
+    async fn claim_request(&self) -> TransportResult<Option<PathBuf>> {
+        let mut entries = tokio::fs::read_dir(self.dir()?).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == REQUEST_EXTENSION) {
+                // Rename so the same request isn't picked up twice
+                let claimed = path.with_extension(PROCESSING_EXTENSION);
+                tokio::fs::rename(&path, &claimed).await?;
+                return Ok(Some(claimed));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Transport for SpoolTransport {
+    type Addr = PathBuf;
 
-impl Transport for TestTransport {
-    type Connection = i32;
+    type Connection = SpoolRequest;
 
-    async fn bind(&mut self, local_addr: impl tokio::net::ToSocketAddrs) -> TransportResult<()> {
-        todo!()
+    async fn bind(&mut self, dir: PathBuf) -> TransportResult<()> {
+        tokio::fs::create_dir_all(&dir).await?;
+        info!("Listening for requests in {}", dir.display());
+        self.dir = Some(dir);
+        Ok(())
     }
 
     async fn accept(&self) -> TransportResult<Self::Connection> {
-        todo!()
+        loop {
+            if let Some(path) = self.claim_request().await? {
+                debug!(path = %path.display(), "Accepted request");
+                return Ok(SpoolRequest { path, read: false });
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
     }
 
-    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
-        todo!()
+    async fn read(&self, request: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        if request.read {
+            // End of the request
+            return Ok(Vec::new());
+        }
+        // Only marked as read once it has been, so that a cancelled read doesn't lose the request
+        let contents = tokio::fs::read(&request.path).await?;
+        request.read = true;
+        Ok(contents)
     }
 
-    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
-        todo!()
+    async fn write(&self, request: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        let response_path = request.path.with_extension(RESPONSE_EXTENSION);
+        debug!(path = %response_path.display(), "Writing response");
+        Ok(tokio::fs::write(response_path, response).await?)
     }
 
-    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
-        todo!()
+    async fn shutdown_conn(&self, request: Self::Connection) -> TransportResult<()> {
+        Ok(tokio::fs::remove_file(request.path).await?)
     }
 }
 
@@ -42,8 +109,8 @@ async fn main() -> yars::Result<()> {
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
-    YarsServer::new(TestTransport, HttpProtocol)
+    YarsServer::new(SpoolTransport::default(), HttpProtocol)
         .get("/", index)
-        .listen("127.0.0.1:8000")
+        .listen("spool")
         .await
 }
@@ -0,0 +1,37 @@
+use yars::{
+    http::{HttpRequest, HttpResponse},
+    protocol::HttpProtocol,
+    transport::{TcpTransport, UringTransport},
+    YarsServer,
+};
+
+async fn hello(_req: HttpRequest) -> anyhow::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().text("Hello, World!"))
+}
+
+/// Serves the same app with io_uring on port 8000 and epoll on port 8001, to compare them with
+/// a load generator, e.g. `wrk -c 1000 http://127.0.0.1:8000`
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_target(false)
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    // Separate tasks, so that each server's connection spans don't end up in the other's logs
+    let uring = tokio::spawn(
+        YarsServer::new(UringTransport::new(), HttpProtocol)
+            .get("/", hello)
+            .listen("127.0.0.1:8000"),
+    );
+    let epoll = tokio::spawn(
+        YarsServer::new(TcpTransport::new(), HttpProtocol)
+            .get("/", hello)
+            .listen("127.0.0.1:8001"),
+    );
+    let (uring, epoll) = tokio::try_join!(uring, epoll)?;
+    uring?;
+    epoll?;
+
+    Ok(())
+}
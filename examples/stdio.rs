@@ -0,0 +1,28 @@
+//! Serves a single connection over stdin/stdout, e.g.
+//! `socat TCP-LISTEN:8000,fork,reuseaddr EXEC:'cargo run -q --example stdio'`
+
+use yars::{
+    http::{HttpRequest, HttpResponse},
+    protocol::HttpProtocol,
+    transport::StdioTransport,
+    YarsServer,
+};
+
+async fn hello(_req: HttpRequest) -> anyhow::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().text("Hello from stdio!"))
+}
+
+#[tokio::main]
+async fn main() -> yars::Result<()> {
+    // stdout is the connection, so logs have to go somewhere else
+    tracing_subscriber::fmt()
+        .with_target(false)
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    YarsServer::new(StdioTransport::new(), HttpProtocol)
+        .get("/", hello)
+        .listen(())
+        .await
+}
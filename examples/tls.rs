@@ -0,0 +1,29 @@
+use yars::{
+    http::{HttpRequest, HttpResponse},
+    protocol::HttpProtocol,
+    transport::{TcpTransport, TlsConfig, TlsTransport},
+    YarsServer,
+};
+
+async fn hello(_req: HttpRequest) -> anyhow::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().text("Hello, TLS!"))
+}
+
+/// Try it with `curl -k https://localhost:8443`
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_target(false)
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    let tls = TlsConfig::self_signed(["localhost".to_string()])?;
+    let transport = TlsTransport::new(TcpTransport::new(), tls)?;
+
+    YarsServer::new(transport, HttpProtocol)
+        .get("/", hello)
+        .listen("127.0.0.1:8443")
+        .await?;
+
+    Ok(())
+}
@@ -1,2 +1,15 @@
 pub const CRLF: &str = "\r\n";
 pub const MAX_REQUEST_SIZE: usize = 1024;
+/// Largest request buffered by the server across reads, before it stops reading and parses it
+pub const MAX_BUFFERED_REQUEST_SIZE: usize = 64 * 1024;
+/// Largest possible UDP payload
+pub const MAX_DATAGRAM_SIZE: usize = 65_535;
+/// Size of the buffer for reading from the upstream of a relayed connection
+pub const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+/// Number of chunks queued in each direction of a relayed connection, before reading from the
+/// sending side waits for the receiving side to catch up
+pub const RELAY_QUEUE_LEN: usize = 8;
+/// Connections accepted by a transport's background task that the server hasn't taken yet, like
+/// a listen backlog. Once it is full, the task stops accepting.
+#[cfg(any(feature = "quic", all(feature = "io-uring", target_os = "linux")))]
+pub const ACCEPT_QUEUE_LEN: usize = 128;
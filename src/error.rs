@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -21,11 +23,65 @@ pub enum TransportError {
     #[error("Transport error: {0}")]
     Generic(String),
 
+    /// No more connections will be accepted, see [`Transport::accept`][crate::transport::Transport::accept]
+    #[error("Transport closed")]
+    Closed,
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
     #[error("TCP error: {0}")]
     Tcp(String),
+
+    #[error("UDP error: {0}")]
+    Udp(String),
+
+    #[error("Unix socket error: {0}")]
+    Unix(String),
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("In-memory transport error: {0}")]
+    Memory(String),
+
+    #[error("PROXY protocol error: {0}")]
+    ProxyProtocol(String),
+
+    #[error("QUIC error: {0}")]
+    Quic(String),
+
+    #[error("io_uring error: {0}")]
+    Uring(String),
+
+    /// See [`YarsServer::first_byte_timeout`][crate::YarsServer::first_byte_timeout]
+    #[error("No bytes received within {0:?}")]
+    FirstByteTimeout(Duration),
+
+    /// See [`YarsServer::request_timeout`][crate::YarsServer::request_timeout]
+    #[error("Request not completed within {0:?}")]
+    RequestTimeout(Duration),
+
+    /// See [`YarsServer::idle_timeout`][crate::YarsServer::idle_timeout]
+    #[error("No bytes received for {0:?} while reading request")]
+    IdleTimeout(Duration),
+
+    /// See [`YarsServer::write_timeout`][crate::YarsServer::write_timeout]
+    #[error("Response not written within {0:?}")]
+    WriteTimeout(Duration),
+}
+
+impl TransportError {
+    /// Whether this is one of the server's connection timeouts
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            Self::FirstByteTimeout(_)
+                | Self::RequestTimeout(_)
+                | Self::IdleTimeout(_)
+                | Self::WriteTimeout(_)
+        )
+    }
 }
 
 #[derive(Debug, Error)]
@@ -0,0 +1,103 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/// A type map of connection-level information, such as peer credentials.
+///
+/// The transport layer provides these for each connection (see
+/// [`Transport::extensions`][crate::transport::Transport::extensions]) and the protocol layer
+/// attaches them to each request (see
+/// [`Protocol::attach_extensions`][crate::protocol::Protocol::attach_extensions]), so that
+/// handlers can access them.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a value, returning the previous value of the same type if there was one
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+
+    /// Moves all values from `other` into `self`, overwriting values of the same type
+    pub fn extend(&mut self, other: Extensions) {
+        self.map.extend(other.map);
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_insert_and_get_by_type() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.get::<u32>().is_none());
+
+        assert_eq!(extensions.insert(1u32), None);
+        assert_eq!(extensions.insert("hello"), None);
+        assert_eq!(extensions.insert(2u32), Some(1));
+
+        assert_eq!(extensions.get::<u32>(), Some(&2));
+        assert_eq!(extensions.get::<&str>(), Some(&"hello"));
+        assert_eq!(extensions.len(), 2);
+
+        *extensions.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(extensions.remove::<u32>(), Some(3));
+        assert!(extensions.get::<u32>().is_none());
+    }
+
+    #[test]
+    fn extend_overwrites_values_of_same_type() {
+        let mut extensions = Extensions::new();
+        extensions.insert(1u32);
+
+        let mut other = Extensions::new();
+        other.insert(2u32);
+        other.insert(true);
+
+        extensions.extend(other);
+        assert_eq!(extensions.get::<u32>(), Some(&2));
+        assert_eq!(extensions.get::<bool>(), Some(&true));
+    }
+}
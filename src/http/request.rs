@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use crate::Extensions;
+
 mod parser;
 
 // TODO?: headers should be map<string, vec<string>>
@@ -14,6 +16,8 @@ pub struct HttpRequest {
     pub uri: String,
     pub headers: Headers,
     pub body: Option<Vec<u8>>,
+    /// Connection-level information provided by the transport layer
+    pub extensions: Extensions,
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Hash)]
@@ -34,6 +38,23 @@ impl HttpRequest {
         // let utf8_str = String::from_utf8(raw).ok()?;
         parser::parse_request(&raw).map(|(_input, req)| req).ok()
     }
+
+    /// Whether `raw` holds all of the headers, and as much of the body as its `Content-Length`
+    pub(crate) fn is_complete(raw: &[u8]) -> bool {
+        let Some(head_len) = raw.windows(4).position(|window| window == b"\r\n\r\n") else {
+            return false;
+        };
+        let content_length = String::from_utf8_lossy(&raw[..head_len])
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value.trim().parse::<usize>());
+        match content_length {
+            Some(Ok(len)) => raw.len() - (head_len + 4) >= len,
+            // Invalid, or no body
+            _ => true,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -48,4 +69,19 @@ mod test {
         dbg!(&req);
         assert!(req.is_some());
     }
+
+    #[test]
+    fn detects_complete_requests() {
+        assert!(!HttpRequest::is_complete(b"GET / HTTP/1.1\r\nHost: a"));
+        assert!(HttpRequest::is_complete(
+            b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"
+        ));
+
+        let post = b"POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello";
+        assert!(HttpRequest::is_complete(post));
+        assert!(!HttpRequest::is_complete(&post[..post.len() - 1]));
+        assert!(HttpRequest::is_complete(
+            b"POST / HTTP/1.1\r\nContent-Length: nope\r\n\r\n"
+        ));
+    }
 }
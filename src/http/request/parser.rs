@@ -14,7 +14,7 @@ use nom::{
 };
 
 use super::{Headers, HttpRequest, RequestMethod};
-use crate::constants::CRLF;
+use crate::{constants::CRLF, Extensions};
 
 // todo?: use newline combinator instead of CRLF
 
@@ -99,6 +99,7 @@ pub(super) fn parse_request(input: &[u8]) -> IResult<&[u8], HttpRequest> {
             uri: uri.to_string(),
             headers,
             body,
+            extensions: Extensions::new(),
         },
     ))
 }
@@ -6,7 +6,7 @@
 //! Make sure to set up a subscriber before running the server.
 //!
 //! ## Example Usage
-//! ```rust
+//! ```rust,no_run
 //! use yars::{
 //!     http::{HttpRequest, HttpResponse, RequestMethod},
 //!     protocol::HttpProtocol,
@@ -43,12 +43,18 @@
 
 mod constants;
 mod error;
+mod extensions;
 mod router;
 mod server;
+#[cfg(all(test, unix))]
+mod test_util;
 
 pub mod http;
 pub mod prelude;
 pub mod protocol;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod socks5;
 pub mod transport;
 
 pub use prelude::*;
@@ -1,6 +1,9 @@
-use crate::{error, server};
+use crate::{error, extensions, server};
 
 pub use error::*;
-pub use server::YarsServer;
+pub use extensions::Extensions;
+#[cfg(unix)]
+pub use server::ThreadPerCore;
+pub use server::{ConnectionInfo, Tunnel, YarsServer};
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -2,13 +2,31 @@
 //!
 //! Supported protocols:
 //! - HTTP
+//! - SOCKS5, relaying connections to upstream TCP targets
 
 pub(crate) mod http;
+pub(crate) mod socks5;
 
 use std::future::Future;
 use std::pin::Pin;
 
 pub use http::HttpProtocol;
+pub use socks5::Socks5Protocol;
+
+use tracing::debug;
+
+use crate::{transport::Transport, Extensions, Tunnel};
+
+/// What the server should do after a read, see [Protocol::negotiate]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Negotiation {
+    /// Keep reading the request
+    Continue,
+    /// Write the reply to the client, then keep reading the request
+    Reply(Vec<u8>),
+    /// Write the reply to the client, then close the connection without handling a request
+    Refuse(Vec<u8>),
+}
 
 // TODO?: rename, things like TCP are protocols. maybe Codec?
 // TODO?: make these functions async?
@@ -28,11 +46,55 @@ pub trait Protocol: Send + Sync + 'static {
     /// Convert raw bytes into a strongly-typed request
     fn parse_request(&self, raw: Vec<u8>) -> Option<Self::Req>;
 
+    /// Whether `raw` holds a whole request, or the server should keep reading from the connection
+    /// first. Requests that can't be valid should count as complete, so they fail to parse.
+    ///
+    /// Defaults to true, so each request is a single read.
+    fn request_complete(&self, _raw: &[u8]) -> bool {
+        true
+    }
+
+    /// Replies to the client before the request is complete, for protocols where the client
+    /// waits for the server during a handshake (e.g. SOCKS5 authentication). Called after every
+    /// read with everything read so far, of which the first `seen` bytes were passed to previous
+    /// calls, so that each part of the handshake is only replied to once.
+    ///
+    /// Defaults to [Negotiation::Continue].
+    fn negotiate(&self, _raw: &[u8], _seen: usize) -> Negotiation {
+        Negotiation::Continue
+    }
+
     /// Convert a strongly-typed response into raw bytes
     fn serialize_response(&self, response: &Self::Res) -> Vec<u8>;
 
     /// Extract a routing key from a request.
     fn extract_routing_key(&self, req: &Self::Req) -> Self::RoutingKey;
+
+    /// Attach connection-level [Extensions] from the transport layer and the server (e.g.
+    /// [ConnectionInfo][crate::ConnectionInfo]) to a request, so they are accessible to handlers.
+    /// Protocols whose requests can carry them should override this.
+    ///
+    /// Defaults to discarding them, which is logged at debug level.
+    fn attach_extensions(&self, _req: &mut Self::Req, extensions: Extensions) {
+        if !extensions.is_empty() {
+            debug!(
+                len = extensions.len(),
+                "Protocol doesn't attach extensions, discarding them"
+            );
+        }
+    }
+
+    /// Takes over the connection through `tunnel` once `response` has been written, e.g. to relay
+    /// it to an upstream, until the connection should be shut down.
+    ///
+    /// Defaults to doing nothing, so the connection is shut down after a single response.
+    fn take_over<T: Transport>(
+        &self,
+        _response: Self::Res,
+        _tunnel: Tunnel<'_, T>,
+    ) -> impl Future<Output = crate::Result<()>> + Send {
+        async { Ok(()) }
+    }
 }
 
 type AnyError = Box<dyn std::error::Error + Send + Sync>;
@@ -1,5 +1,8 @@
 use super::Protocol;
-use crate::http::{HttpRequest, HttpResponse, RequestMethod};
+use crate::{
+    http::{HttpRequest, HttpResponse, RequestMethod},
+    Extensions,
+};
 
 /// HTTP 1.1
 pub struct HttpProtocol;
@@ -15,6 +18,10 @@ impl Protocol for HttpProtocol {
         HttpRequest::parse_request(raw)
     }
 
+    fn request_complete(&self, raw: &[u8]) -> bool {
+        HttpRequest::is_complete(raw)
+    }
+
     fn serialize_response(&self, response: &Self::Res) -> Vec<u8> {
         let mut buf = Vec::new();
 
@@ -33,6 +40,10 @@ impl Protocol for HttpProtocol {
             method: req.method,
         }
     }
+
+    fn attach_extensions(&self, req: &mut Self::Req, extensions: Extensions) {
+        req.extensions.extend(extensions);
+    }
 }
 
 /// HTTP routing is based on the URI and the request method
@@ -0,0 +1,558 @@
+use std::future::pending;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc,
+    time::{sleep_until, Instant},
+};
+use tracing::{trace, warn};
+
+use super::{Negotiation, Protocol};
+use crate::{
+    constants::{RELAY_BUFFER_SIZE, RELAY_QUEUE_LEN},
+    socks5::{
+        method, parser, Command, Socks5Request, Socks5Response, TargetAddr, AUTH_VERSION, VERSION,
+    },
+    transport::Transport,
+    Extensions, TransportError, Tunnel,
+};
+
+type Authenticate = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+/// SOCKS5 (RFC 1928), routing requests by their [Command].
+///
+/// Clients are accepted without authentication, or with a username and password (RFC 1929) if
+/// [`authenticate`][Self::authenticate] is set. Handlers decide what to do with each request,
+/// e.g. [`socks5::connect`][crate::socks5::connect] connects to the target of a `CONNECT`
+/// request, after which the client is relayed to it until either side closes the connection.
+///
+/// ```rust,no_run
+/// use yars::{
+///     protocol::Socks5Protocol,
+///     socks5::{self, Command},
+///     transport::TcpTransport,
+///     YarsServer,
+/// };
+///
+/// #[tokio::main]
+/// async fn main() -> yars::Result<()> {
+///     let protocol = Socks5Protocol::new()
+///         .authenticate(|username, password| username == "user" && password == "secret");
+///
+///     YarsServer::new(TcpTransport::default(), protocol)
+///         .route(Command::Connect, socks5::connect)
+///         .default_handler(socks5::not_supported)
+///         .listen("127.0.0.1:1080")
+///         .await
+/// }
+/// ```
+#[derive(Default)]
+pub struct Socks5Protocol {
+    authenticate: Option<Box<Authenticate>>,
+}
+
+impl Socks5Protocol {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require clients to authenticate with a username and password, which are accepted if
+    /// `authenticate` returns true. Clients that don't offer username/password authentication are
+    /// refused.
+    pub fn authenticate(
+        mut self,
+        authenticate: impl Fn(&str, &str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.authenticate = Some(Box::new(authenticate));
+        self
+    }
+
+    /// The authentication method clients must use
+    fn method(&self) -> u8 {
+        match self.authenticate {
+            Some(_) => method::USERNAME_PASSWORD,
+            None => method::NO_AUTH,
+        }
+    }
+
+    /// Parses the whole handshake, returning the username the client authenticated with and its
+    /// request.
+    ///
+    /// Clients that don't offer the method or fail to authenticate have already been refused
+    /// while negotiating, so neither is checked again.
+    fn handshake<'a>(
+        &self,
+        raw: &'a [u8],
+    ) -> nom::IResult<&'a [u8], (Option<String>, Command, TargetAddr)> {
+        let (input, _methods) = parser::greeting(raw)?;
+        let (input, username) = match self.authenticate {
+            Some(_) => {
+                let (input, (username, _password)) = parser::credentials(input)?;
+                (input, Some(username))
+            }
+            None => (input, None),
+        };
+        let (input, (command, target)) = parser::request(input)?;
+        Ok((input, (username, command, target)))
+    }
+}
+
+impl std::fmt::Debug for Socks5Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Socks5Protocol")
+            .field("authenticate", &self.authenticate.is_some())
+            .finish()
+    }
+}
+
+impl Protocol for Socks5Protocol {
+    type Req = Socks5Request;
+
+    type Res = Socks5Response;
+
+    type RoutingKey = Command;
+
+    fn parse_request(&self, raw: Vec<u8>) -> Option<Self::Req> {
+        let (rest, (username, command, target)) = self.handshake(&raw).ok()?;
+        if !rest.is_empty() {
+            trace!(len = rest.len(), "Client sent data before the SOCKS5 reply");
+        }
+        Some(Socks5Request {
+            command,
+            target,
+            username,
+            pending: rest.to_vec(),
+            extensions: Extensions::new(),
+        })
+    }
+
+    fn request_complete(&self, raw: &[u8]) -> bool {
+        // Invalid handshakes are complete, so that they fail to parse
+        !matches!(self.handshake(raw), Err(nom::Err::Incomplete(_)))
+    }
+
+    fn negotiate(&self, raw: &[u8], seen: usize) -> Negotiation {
+        // Only the parts of the handshake that ended in the latest read are replied to
+        let is_new = |rest: &[u8]| raw.len() - rest.len() > seen;
+
+        // Invalid greetings are left to fail to parse
+        let Ok((rest, methods)) = parser::greeting(raw) else {
+            return Negotiation::Continue;
+        };
+        let mut reply = Vec::new();
+        if is_new(rest) {
+            if !methods.contains(&self.method()) {
+                warn!(?methods, "No acceptable SOCKS5 authentication method");
+                return Negotiation::Refuse(vec![VERSION, method::NO_ACCEPTABLE_METHODS]);
+            }
+            reply.extend([VERSION, self.method()]);
+        }
+
+        if let Some(authenticate) = &self.authenticate {
+            if let Ok((rest, (username, password))) = parser::credentials(rest) {
+                if is_new(rest) {
+                    // Any status other than 0 is a failure
+                    let success = authenticate(&username, &password);
+                    reply.extend([AUTH_VERSION, !success as u8]);
+                    if !success {
+                        warn!(username, "SOCKS5 authentication failed");
+                        return Negotiation::Refuse(reply);
+                    }
+                }
+            }
+        }
+
+        match reply.is_empty() {
+            true => Negotiation::Continue,
+            false => Negotiation::Reply(reply),
+        }
+    }
+
+    fn serialize_response(&self, response: &Self::Res) -> Vec<u8> {
+        response.to_bytes()
+    }
+
+    fn extract_routing_key(&self, req: &Self::Req) -> Self::RoutingKey {
+        req.command
+    }
+
+    fn attach_extensions(&self, req: &mut Self::Req, extensions: Extensions) {
+        req.extensions.extend(extensions);
+    }
+
+    async fn take_over<T: Transport>(
+        &self,
+        response: Self::Res,
+        tunnel: Tunnel<'_, T>,
+    ) -> crate::Result<()> {
+        match response.upstream {
+            Some(upstream) => relay(tunnel, upstream, response.pending).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copies bytes between the client and `upstream`, until the upstream closes the connection,
+/// starting with the bytes the client sent with its request. Once the client closes its side,
+/// the upstream can still respond.
+///
+/// Each direction of the upstream is copied on its own, through a bounded queue, so that neither
+/// direction waits for the other. The client's side is only waited on by writes to it, which
+/// are subject to the write timeout.
+async fn relay<T: Transport>(
+    tunnel: Tunnel<'_, T>,
+    upstream: TcpStream,
+    pending: Vec<u8>,
+) -> crate::Result<()> {
+    let (upstream_read, upstream_write) = upstream.into_split();
+    let (to_upstream, to_upstream_rx) = mpsc::channel(RELAY_QUEUE_LEN);
+    let (from_upstream_tx, from_upstream) = mpsc::channel(RELAY_QUEUE_LEN);
+
+    // Finished once the upstream has closed its side and everything it sent has been relayed.
+    // Copying to the upstream finishing first isn't an error, as it can still respond.
+    tokio::select! {
+        result = relay_client(tunnel, to_upstream, from_upstream) => result,
+        Err(err) = write_upstream(upstream_write, pending, to_upstream_rx) => Err(err),
+        Err(err) = read_upstream(upstream_read, from_upstream_tx) => Err(err),
+    }
+}
+
+/// Reads from the client into `to_upstream` and writes what arrives in `from_upstream` to the
+/// client, until `from_upstream` is closed
+async fn relay_client<T: Transport>(
+    mut tunnel: Tunnel<'_, T>,
+    to_upstream: mpsc::Sender<Vec<u8>>,
+    mut from_upstream: mpsc::Receiver<Vec<u8>>,
+) -> crate::Result<()> {
+    // Dropped once the client closes its side, so that the upstream's side is shut down
+    let mut to_upstream = Some(to_upstream);
+    let idle_timeout = tunnel.idle_timeout();
+    loop {
+        let idle_deadline = idle_timeout.map(|limit| Instant::now() + limit);
+        let read_client = async {
+            // Only read once the chunk can be queued, both of which are cancel safe
+            let permit = match &to_upstream {
+                Some(to_upstream) => to_upstream.reserve().await,
+                None => return pending().await,
+            };
+            (permit, tunnel.read().await)
+        };
+
+        let relayed = tokio::select! {
+            (permit, chunk) = read_client => {
+                let chunk = chunk?;
+                let closed = chunk.is_empty();
+                // Only fails if copying to the upstream has failed, which ends the relay
+                if let (false, Ok(permit)) = (closed, permit) {
+                    permit.send(chunk);
+                }
+                Relayed::FromClient { closed }
+            }
+            chunk = from_upstream.recv() => Relayed::FromUpstream(chunk),
+            _ = sleep_until_deadline(idle_deadline) => {
+                let limit = idle_timeout.unwrap_or_default();
+                warn!(timeout = "idle", ?limit, "Connection timed out");
+                return Err(TransportError::IdleTimeout(limit).into());
+            }
+        };
+        match relayed {
+            Relayed::FromClient { closed: true } => to_upstream = None,
+            Relayed::FromClient { closed: false } => {}
+            Relayed::FromUpstream(Some(chunk)) => tunnel.write(&chunk).await?,
+            Relayed::FromUpstream(None) => return Ok(()),
+        }
+    }
+}
+
+/// What [relay_client] woke up for
+enum Relayed {
+    /// Read from the client and queued for the upstream, unless the client has `closed` its side
+    FromClient { closed: bool },
+    /// A chunk from the upstream, or `None` once the upstream has closed its side
+    FromUpstream(Option<Vec<u8>>),
+}
+
+/// Waits until `deadline`, or forever if there isn't one
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => pending().await,
+    }
+}
+
+/// Writes `pending`, then everything sent to `chunks` to the upstream, and shuts down its side
+/// once `chunks` is closed
+async fn write_upstream(
+    mut upstream: OwnedWriteHalf,
+    pending: Vec<u8>,
+    mut chunks: mpsc::Receiver<Vec<u8>>,
+) -> crate::Result<()> {
+    // Already counted as read with the request
+    upstream.write_all(&pending).await?;
+    while let Some(chunk) = chunks.recv().await {
+        upstream.write_all(&chunk).await?;
+    }
+    upstream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads from the upstream into `chunks`, until it closes its side
+async fn read_upstream(
+    mut upstream: OwnedReadHalf,
+    chunks: mpsc::Sender<Vec<u8>>,
+) -> crate::Result<()> {
+    loop {
+        let mut buf = vec![0; RELAY_BUFFER_SIZE];
+        let len = upstream.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.truncate(len);
+        if chunks.send(buf).await.is_err() {
+            // The relay has finished
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::sync::Arc;
+
+    use tokio::{io::DuplexStream, net::TcpListener};
+
+    use super::*;
+    use crate::{
+        socks5,
+        transport::{MemoryClient, MemoryTransport},
+        YarsServer,
+    };
+
+    /// Starts an upstream that echoes everything back, returning its address
+    async fn echo_upstream() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // Small buffers, so that the upstream soon stops reading while it can't write
+            let socket = socket2::SockRef::from(&stream);
+            socket.set_recv_buffer_size(16 * 1024).unwrap();
+            socket.set_send_buffer_size(16 * 1024).unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+        addr
+    }
+
+    fn start(protocol: Socks5Protocol) -> MemoryClient {
+        start_with(protocol, |server| server)
+    }
+
+    /// Like [start], with further server options set by `configure`
+    fn start_with(
+        protocol: Socks5Protocol,
+        configure: impl FnOnce(
+            YarsServer<MemoryTransport, Socks5Protocol>,
+        ) -> YarsServer<MemoryTransport, Socks5Protocol>,
+    ) -> MemoryClient {
+        let (transport, client) = MemoryTransport::new();
+        let server = YarsServer::new(transport, protocol)
+            .route(Command::Connect, socks5::connect)
+            .default_handler(socks5::not_supported);
+        tokio::spawn(configure(server).listen(()));
+        client
+    }
+
+    async fn expect(stream: &mut DuplexStream, expected: &[u8]) {
+        let mut buf = vec![0; expected.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    /// CONNECT request to `addr`
+    fn connect_request(addr: std::net::SocketAddr) -> Vec<u8> {
+        let std::net::SocketAddr::V4(addr) = addr else {
+            panic!("Expected an IPv4 address");
+        };
+        let mut request = vec![VERSION, 0x01, 0x00, 0x01];
+        request.extend(addr.ip().octets());
+        request.extend(addr.port().to_be_bytes());
+        request
+    }
+
+    #[tokio::test]
+    async fn relays_connections_without_authentication() {
+        let upstream = echo_upstream().await;
+        let client = start(Socks5Protocol::new());
+
+        let mut stream = client.connect().unwrap();
+        stream.write_all(&[VERSION, 2, 0x00, 0x02]).await.unwrap();
+        expect(&mut stream, &[VERSION, method::NO_AUTH]).await;
+
+        stream.write_all(&connect_request(upstream)).await.unwrap();
+        let mut reply = [0; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [VERSION, 0x00, 0x00, 0x01]);
+        assert_eq!(reply[4..8], [127, 0, 0, 1]);
+
+        stream.write_all(b"hello").await.unwrap();
+        expect(&mut stream, b"hello").await;
+        stream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relays_data_sent_with_request() {
+        let upstream = echo_upstream().await;
+        let client = start(Socks5Protocol::new());
+
+        // Without waiting for either reply
+        let mut stream = client.connect().unwrap();
+        let mut handshake = vec![VERSION, 1, 0x00];
+        handshake.extend(connect_request(upstream));
+        handshake.extend(b"hello");
+        stream.write_all(&handshake).await.unwrap();
+
+        expect(&mut stream, &[VERSION, method::NO_AUTH]).await;
+        expect(&mut stream, &[VERSION, 0x00, 0x00, 0x01]).await;
+        let mut bound = [0; 6];
+        stream.read_exact(&mut bound).await.unwrap();
+        expect(&mut stream, b"hello").await;
+    }
+
+    /// Connects to `upstream` without authentication, returning the relayed stream
+    async fn open_tunnel(client: &MemoryClient, upstream: std::net::SocketAddr) -> DuplexStream {
+        let mut stream = client.connect().unwrap();
+        let mut handshake = vec![VERSION, 1, 0x00];
+        handshake.extend(connect_request(upstream));
+        stream.write_all(&handshake).await.unwrap();
+
+        expect(&mut stream, &[VERSION, method::NO_AUTH]).await;
+        expect(&mut stream, &[VERSION, 0x00, 0x00, 0x01]).await;
+        let mut bound = [0; 6];
+        stream.read_exact(&mut bound).await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn relays_in_both_directions_at_once() {
+        let upstream = echo_upstream().await;
+        let client = start(Socks5Protocol::new());
+        let stream = open_tunnel(&client, upstream).await;
+
+        // Far more than fits in the buffers along the way, so the relay has to keep reading from
+        // both sides while the upstream echoes
+        let sent: Vec<u8> = (0..8 * 1024 * 1024).map(|i: u32| i as u8).collect();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let write = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                writer.write_all(&sent).await.unwrap();
+                writer.shutdown().await.unwrap();
+            }
+        });
+
+        let mut received = Vec::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(30),
+            reader.read_to_end(&mut received),
+        )
+        .await
+        .expect("relay stalled")
+        .unwrap();
+        write.await.unwrap();
+        assert!(received == sent, "received {} bytes", received.len());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_tunnels() {
+        let upstream = echo_upstream().await;
+        let client = start_with(Socks5Protocol::new(), |server| {
+            server.idle_timeout(std::time::Duration::from_millis(100))
+        });
+        let mut stream = open_tunnel(&client, upstream).await;
+
+        stream.write_all(b"hello").await.unwrap();
+        expect(&mut stream, b"hello").await;
+        let mut rest = Vec::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stream.read_to_end(&mut rest),
+        )
+        .await
+        .expect("tunnel wasn't closed")
+        .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_username_and_password() {
+        let upstream = echo_upstream().await;
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let protocol = Socks5Protocol::new().authenticate({
+            let attempts = attempts.clone();
+            move |username, password| {
+                attempts.fetch_add(1, Relaxed);
+                username == "user" && password == "secret"
+            }
+        });
+        let client = start(protocol);
+
+        // Without offering username/password authentication
+        let response = client.send(&[VERSION, 1, 0x00]).await.unwrap();
+        assert_eq!(response, [VERSION, method::NO_ACCEPTABLE_METHODS]);
+
+        // With the wrong password
+        let response = client
+            .send(b"\x05\x01\x02\x01\x04user\x05wrong")
+            .await
+            .unwrap();
+        assert_eq!(response, [VERSION, method::USERNAME_PASSWORD, 0x01, 0x01]);
+
+        // Sending the whole handshake at once
+        let mut stream = client.connect().unwrap();
+        let mut handshake = b"\x05\x01\x02\x01\x04user\x06secret".to_vec();
+        handshake.extend(connect_request(upstream));
+        stream.write_all(&handshake).await.unwrap();
+        expect(
+            &mut stream,
+            &[VERSION, method::USERNAME_PASSWORD, 0x01, 0x00],
+        )
+        .await;
+        expect(&mut stream, &[VERSION, 0x00, 0x00, 0x01]).await;
+        let mut bound = [0; 6];
+        stream.read_exact(&mut bound).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        expect(&mut stream, b"hello").await;
+
+        assert_eq!(attempts.load(Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn replies_with_failures() {
+        // Nothing listens on the port once the listener is dropped
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = start(Socks5Protocol::new());
+
+        let mut request = vec![VERSION, 1, 0x00];
+        request.extend(connect_request(closed));
+        let response = client.send(&request).await.unwrap();
+        assert_eq!(response[..4], [VERSION, method::NO_AUTH, VERSION, 0x05]);
+
+        let mut request = vec![VERSION, 1, 0x00];
+        request.extend(b"\x05\x02\x00\x03\x09localhost\x00\x50");
+        let response = client.send(&request).await.unwrap();
+        assert_eq!(response[..4], [VERSION, method::NO_AUTH, VERSION, 0x07]);
+    }
+}
@@ -1,17 +1,78 @@
+use std::io::ErrorKind;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
-
-use tokio::{net::ToSocketAddrs, signal, task::JoinHandle};
-use tracing::{debug, error, error_span, info, info_span, trace, warn, Instrument};
+use std::time::{Duration, SystemTime};
+
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+use tokio::{
+    pin, signal,
+    sync::Semaphore,
+    task::JoinSet,
+    time::{sleep, timeout, timeout_at, Instant},
+};
+use tracing::{debug, error, error_span, field, info, info_span, trace, warn, Instrument, Span};
 
 use crate::{
-    protocol::{HttpProtocol, Protocol, ToHandler},
+    constants::MAX_BUFFERED_REQUEST_SIZE,
+    protocol::{HttpProtocol, Negotiation, Protocol, ToHandler},
     router::Router,
-    transport::{TcpTransport, Transport},
-    Result,
+    transport::{ByteCount, TcpTransport, Transport},
+    Result, TransportError,
 };
 
+mod connection_info;
+#[cfg(unix)]
+mod hot_restart;
+#[cfg(unix)]
+mod thread_per_core;
+mod tunnel;
+#[cfg(unix)]
+mod workers;
+
+pub use connection_info::ConnectionInfo;
+#[cfg(unix)]
+pub use thread_per_core::ThreadPerCore;
+pub use tunnel::Tunnel;
+
+/// First delay before accepting again after a transient error, doubling on each further error
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+/// Longest delay before accepting again after a transient error
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Connection timeouts, none of which are set by default
+#[derive(Debug, Default, Clone, Copy)]
+struct Timeouts {
+    first_byte: Option<Duration>,
+    request: Option<Duration>,
+    idle: Option<Duration>,
+    write: Option<Duration>,
+}
+
+/// Which connection IDs a server hands out, so that the servers of [ThreadPerCore] each hand out
+/// different ones without sharing a counter: the `n`th connection gets `n * stride + offset`
+#[derive(Debug, Clone, Copy)]
+struct ConnIds {
+    offset: usize,
+    stride: usize,
+}
+
+impl Default for ConnIds {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            stride: 1,
+        }
+    }
+}
+
+impl ConnIds {
+    fn nth(self, n: usize) -> usize {
+        n.wrapping_mul(self.stride).wrapping_add(self.offset)
+    }
+}
+
 // TODO: some sort of config file: max_connections, max_request_size, etc
 // TODO? type safe builder for build YarsServer when have more options
 
@@ -26,7 +87,7 @@ use crate::{
 /// [`Sync`].
 ///
 /// # Example Usage
-/// ```rust
+/// ```rust,no_run
 /// use yars::{
 ///     http::{HttpRequest, HttpResponse, RequestMethod},
 ///     protocol::HttpProtocol,
@@ -70,6 +131,16 @@ where
     protocol: P,
     router: Router<P>,
     conn_counter: AtomicUsize,
+    conn_ids: ConnIds,
+    /// Limits concurrent connections, see [YarsServer::max_connections]
+    connection_limit: Option<Arc<Semaphore>>,
+    timeouts: Timeouts,
+    /// Signal that triggers a hot restart, see [YarsServer::hot_restart_on]
+    #[cfg(unix)]
+    hot_restart: Option<SignalKind>,
+    /// Number of worker processes, see [YarsServer::workers]
+    #[cfg(unix)]
+    workers: Option<usize>,
 }
 
 // Our default is a HTTP server that accepts TCP connections
@@ -81,6 +152,13 @@ impl YarsServer<TcpTransport, HttpProtocol> {
             protocol: HttpProtocol,
             router: Router::new(),
             conn_counter: AtomicUsize::new(0),
+            conn_ids: ConnIds::default(),
+            connection_limit: None,
+            timeouts: Timeouts::default(),
+            #[cfg(unix)]
+            hot_restart: None,
+            #[cfg(unix)]
+            workers: None,
         }
     }
 }
@@ -97,6 +175,13 @@ where
             protocol,
             router: Router::new(),
             conn_counter: AtomicUsize::new(0),
+            conn_ids: ConnIds::default(),
+            connection_limit: None,
+            timeouts: Timeouts::default(),
+            #[cfg(unix)]
+            hot_restart: None,
+            #[cfg(unix)]
+            workers: None,
         }
     }
 
@@ -116,83 +201,251 @@ where
         self
     }
 
+    /// Handle at most `max` connections at once. Once the limit is reached, no more connections
+    /// are accepted until one closes, so new connections wait in the transport's backlog.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.connection_limit = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Close connections that haven't sent any bytes within `limit` of being accepted, with
+    /// [`TransportError::FirstByteTimeout`]
+    pub fn first_byte_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.first_byte = Some(limit);
+        self
+    }
+
+    /// Close connections that haven't sent a complete request within `limit` of being accepted,
+    /// with [`TransportError::RequestTimeout`]. This stops clients that send a request slowly
+    /// from holding a connection open (slowloris).
+    pub fn request_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.request = Some(limit);
+        self
+    }
+
+    /// Close connections that stop sending for `limit` partway through a request, with
+    /// [`TransportError::IdleTimeout`]. Connections are closed after each request, so this is
+    /// the longest wait between reads. A [Tunnel] that the protocol takes over (e.g. SOCKS5) is
+    /// closed once nothing has been relayed in either direction for `limit`.
+    pub fn idle_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.idle = Some(limit);
+        self
+    }
+
+    /// Close connections that don't accept the whole response within `limit`, with
+    /// [`TransportError::WriteTimeout`]
+    pub fn write_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.write = Some(limit);
+        self
+    }
+
+    /// Hot restart the server when `signal` (e.g. `SIGUSR2`) is received, to deploy a new binary
+    /// or config without dropping connections.
+    ///
+    /// The current executable is started again with the same arguments, and the transport's
+    /// listening sockets are handed over to it (see [`Transport::listeners`]). The transport of
+    /// the new process adopts them when it binds to the same address. Once it is listening,
+    /// this server stops accepting connections, waits for open connections to finish and
+    /// returns from [`listen`][YarsServer::listen].
+    ///
+    /// If the new process exits before it is listening, this server carries on as before.
+    #[cfg(unix)]
+    pub fn hot_restart_on(mut self, signal: SignalKind) -> Self {
+        self.hot_restart = Some(signal);
+        self
+    }
+
+    /// Run the server in `count` worker processes, so that a crash (e.g. a handler aborting, or
+    /// running out of memory) only takes down a single worker.
+    ///
+    /// [`listen`][YarsServer::listen] then supervises the workers instead of listening itself.
+    /// Each worker is the current executable run again with the same arguments, in which
+    /// [`listen`][YarsServer::listen] binds the transport with `SO_REUSEPORT` (see
+    /// [`Transport::set_reuse_port`]), so that the kernel balances connections between them.
+    /// Workers that exit unsuccessfully are restarted. On Ctrl-C, the supervisor stops all
+    /// workers and returns once they have exited.
+    #[cfg(unix)]
+    pub fn workers(mut self, count: usize) -> Self {
+        self.workers = Some(count);
+        self
+    }
+
     /// Starts the server. This will bind the transport to the given address and start listening
     /// for incoming connections.
-    pub async fn listen<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
+    pub async fn listen(mut self, addr: impl Into<T::Addr>) -> Result<()> {
         // TODO?: debug print type of transport and protocol
         debug!("{:#?}", self.router);
 
-        self.transport.bind(addr).await?;
+        #[cfg(unix)]
+        if let Some(count) = self.workers {
+            match workers::worker_id() {
+                Some(id) => {
+                    info!(worker = id, "Running as worker");
+                    self.transport.set_reuse_port(true)?;
+                }
+                None => return workers::supervise(count).await,
+            }
+        }
+
+        self.transport.bind(addr.into()).await?;
+        // Let the previous process know it can stop accepting, if this is a hot restart
+        #[cfg(unix)]
+        crate::transport::listen_fds::notify_ready();
+        let server = Arc::new(self);
 
-        // Tbh I have no idea if I am doing this correctly
-        // a TaskTracker or similar may be better
-        // https://docs.rs/tokio-util/latest/tokio_util/task/task_tracker/struct.TaskTracker.html
-        let mut conn_handles = Vec::new();
+        // Connection tasks, which are reaped as they finish
+        let mut connections = JoinSet::new();
 
-        tokio::select! {
-            _ = self.listen_inner(&mut conn_handles) => {
-                // This should never happen
+        let (result, handed_over) = tokio::select! {
+            result = server.clone().listen_inner(&mut connections) => {
                 info!("Server shutting down");
+                (result, false)
             },
             _ = signal::ctrl_c() => {
                 info!("Received SIGINT, shutting down");
+                (Ok(()), false)
             },
+            result = server.handed_over() => match result {
+                Ok(pid) => {
+                    info!(pid, "Handed listeners over to new process, shutting down");
+                    (Ok(()), true)
+                }
+                Err(err) => (Err(err), false),
+            },
+        };
+
+        if handed_over {
+            info!("Waiting for open connections to finish");
+            wait_for_connections(&mut connections).await;
+            // The listeners now belong to the new process, so the transport isn't shut down
+            return result;
         }
 
-        // TODO: close/abort all open connection tasks
-        // idk if clearing will actually close the tasks - we don't even need to clear really, can just drop
-        // but then really is there a point to storing them in the first place?
-        conn_handles.clear();
+        // Open connections are dropped along with their tasks
+        connections.abort_all();
 
-        // self.transport.close().await?;
-        Ok(())
+        server.transport.shutdown().await?;
+        result
     }
 
-    async fn listen_inner(self, conn_handles: &mut Vec<JoinHandle<()>>) -> Result<()> {
-        let server = Arc::new(self);
+    /// Resolves once the listeners have been handed over to a new process by a hot restart, with
+    /// its PID. Never resolves if hot restarts aren't enabled.
+    async fn handed_over(&self) -> Result<u32> {
+        #[cfg(unix)]
+        if let Some(signal) = self.hot_restart {
+            return hot_restart::hand_over(signal, &self.transport).await;
+        }
+        std::future::pending().await
+    }
 
+    async fn listen_inner(self: Arc<Self>, connections: &mut JoinSet<()>) -> Result<()> {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
         loop {
-            let conn_id = server.conn_counter.fetch_add(1, Relaxed);
-            // TODO?: also include remote addr - but then that would have to get it from transport.accept
-            // tbh could use empty value and let transport layer handle it
-            // actually no cos then we would have to pass the span to the transport layer
-            // https://docs.rs/tracing/latest/tracing/#recording-fields
+            let permit = match &self.connection_limit {
+                Some(limit) => {
+                    if limit.available_permits() == 0 {
+                        debug!("Connection limit reached, waiting for a connection to close");
+                    }
+                    // The semaphore is never closed
+                    Some(
+                        limit
+                            .clone()
+                            .acquire_owned()
+                            .await
+                            .expect("semaphore closed"),
+                    )
+                }
+                None => None,
+            };
+
+            let conn_id = self.conn_ids.nth(self.conn_counter.fetch_add(1, Relaxed));
             // TODO?: route as later param - but how would we pass span to task?
-            let conn_span = error_span!("connection", id = conn_id);
+            // The peer is recorded once known, as the transport may only know it after reading
+            // https://docs.rs/tracing/latest/tracing/#recording-fields
+            let conn_span = error_span!(
+                "connection",
+                id = conn_id,
+                peer = field::Empty,
+                bytes_read = field::Empty,
+                bytes_written = field::Empty,
+            );
             // Enter the span before accepting connection so the connection ID is included in
             // transport layer logs, which could include peer/remote address
             let _entered = conn_span.enter();
 
             // Accept connection with transport layer
-            let mut conn = server.transport.accept().await?;
+            let mut conn = match accept_reaping(&self.transport, connections).await {
+                Ok(conn) => conn,
+                Err(TransportError::Closed) => {
+                    info!("Transport closed, waiting for open connections to finish");
+                    wait_for_connections(connections).await;
+                    return Ok(());
+                }
+                Err(err) if is_transient(&err) => {
+                    warn!(?err, ?backoff, "Error accepting connection, retrying");
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            backoff = ACCEPT_BACKOFF_MIN;
+            let accepted_at = SystemTime::now();
+            self.record_peer(&conn_span, &conn);
 
             // Handle connection in new task
-            let server = server.clone();
-            let handle = tokio::spawn(
+            let server = self.clone();
+            connections.spawn(
                 async move {
-                    if let Err(e) = server.handle_connection(&mut conn).await {
-                        error!(?e, "Error handling connection");
+                    let mut bytes = ByteCount::default();
+                    let handled = server
+                        .handle_connection(&mut conn, conn_id, accepted_at, &mut bytes)
+                        .await;
+                    match handled {
+                        Ok(()) => {}
+                        // Already logged when the timeout expired
+                        Err(crate::Error::Transport(e)) if e.is_timeout() => {}
+                        Err(e) => error!(?e, "Error handling connection"),
                     }
                     if let Err(e) = server.transport.shutdown_conn(conn).await {
                         error!(?e, "Error shutting down connection");
                     }
+                    let span = Span::current();
+                    span.record("bytes_read", bytes.read);
+                    span.record("bytes_written", bytes.written);
+                    debug!("Connection closed");
+                    // Let the next connection be accepted
+                    drop(permit);
                 }
                 .in_current_span(),
             );
-
-            conn_handles.push(handle);
         }
     }
 
-    async fn handle_connection(&self, conn: &mut T::Connection) -> Result<()> {
-        // Read request from connection with transport layer
-        trace!("Attempting to read from connection");
-        let raw_request = self
-            .transport
-            .read(conn)
-            .instrument(info_span!("read_connection"))
-            .await?;
+    fn record_peer(&self, span: &Span, conn: &T::Connection) {
+        if let Some(peer) = self.transport.peer_addr(conn) {
+            span.record("peer", field::display(peer));
+        }
+    }
 
+    /// Handles a request on `conn`, counting the bytes read and written through the transport in
+    /// `bytes`
+    async fn handle_connection(
+        &self,
+        conn: &mut T::Connection,
+        conn_id: usize,
+        accepted_at: SystemTime,
+        bytes: &mut ByteCount,
+    ) -> Result<()> {
+        let raw_request = self.read_request(conn, bytes).await?;
+
+        // e.g. the client address may have been forwarded by a proxy
+        self.record_peer(&Span::current(), conn);
+
+        let Some(raw_request) = raw_request else {
+            // Already logged when the protocol refused it
+            return Ok(());
+        };
         if raw_request.is_empty() {
             debug!("Empty request, maybe connection closed");
             return Ok(());
@@ -200,11 +453,22 @@ where
 
         // Parse request bytes using protocol layer
         trace!(len = raw_request.len(), "Parsing request");
-        let Some(request) = self.protocol.parse_request(raw_request) else {
+        let Some(mut request) = self.protocol.parse_request(raw_request) else {
             warn!("Failed to parse request");
             return Ok(());
         };
 
+        // Attach connection-level information from the transport layer
+        let mut extensions = self.transport.extensions(conn);
+        extensions.insert(ConnectionInfo {
+            id: conn_id,
+            peer_addr: self.transport.peer_addr(conn),
+            local_addr: self.transport.conn_local_addr(conn),
+            accepted_at,
+            transport: self.transport.kind(conn),
+        });
+        self.protocol.attach_extensions(&mut request, extensions);
+
         // Extract routing key using protocol layer
         trace!("Extracting routing key");
         let routing_key = self.protocol.extract_routing_key(&request);
@@ -228,14 +492,195 @@ where
         let response_bytes = self.protocol.serialize_response(&response);
 
         // Write response bytes to connection with transport layer
-        trace!("Attempting to write to connection");
-        self.transport
-            .write(conn, &response_bytes)
-            .instrument(info_span!("write_connection"))
-            .await?;
+        self.write(conn, &response_bytes, bytes).await?;
+
+        // e.g. relay a tunnel that the response opened
+        let tunnel = Tunnel::new(&self.transport, conn, bytes, self.timeouts);
+        self.protocol.take_over(response, tunnel).await
+    }
 
+    /// Writes `response` to `conn` within the write timeout
+    async fn write(
+        &self,
+        conn: &mut T::Connection,
+        response: &[u8],
+        bytes: &mut ByteCount,
+    ) -> Result<()> {
+        trace!("Attempting to write to connection");
+        write_within(&self.transport, conn, response, self.timeouts.write).await?;
+        bytes.written += response.len() as u64;
         Ok(())
     }
+
+    /// Reads from `conn` until the protocol has a complete request, the connection is closed, or
+    /// a timeout expires. Returns `None` if the protocol refused the connection while
+    /// negotiating.
+    async fn read_request(
+        &self,
+        conn: &mut T::Connection,
+        bytes: &mut ByteCount,
+    ) -> Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        let mut raw_request = Vec::new();
+        loop {
+            // The first bytes and the idle timeouts apply before and after they have arrived
+            let between_reads = match raw_request.is_empty() {
+                true => self.timeouts.first_byte.map(|limit| {
+                    (
+                        started + limit,
+                        "first_byte",
+                        TransportError::FirstByteTimeout(limit),
+                    )
+                }),
+                false => self.timeouts.idle.map(|limit| {
+                    (
+                        Instant::now() + limit,
+                        "idle",
+                        TransportError::IdleTimeout(limit),
+                    )
+                }),
+            };
+            let whole_request = self.timeouts.request.map(|limit| {
+                (
+                    started + limit,
+                    "request",
+                    TransportError::RequestTimeout(limit),
+                )
+            });
+            let deadline = [between_reads, whole_request]
+                .into_iter()
+                .flatten()
+                .min_by_key(|(deadline, ..)| *deadline);
+
+            // Read request from connection with transport layer
+            trace!("Attempting to read from connection");
+            let read = self
+                .transport
+                .read(conn)
+                .instrument(info_span!("read_connection"));
+            let chunk = match deadline {
+                Some((deadline, kind, err)) => match timeout_at(deadline, read).await {
+                    Ok(chunk) => chunk?,
+                    Err(_) => {
+                        warn!(timeout = kind, %err, "Connection timed out");
+                        return Err(err.into());
+                    }
+                },
+                None => read.await?,
+            };
+            bytes.read += chunk.len() as u64;
+
+            if chunk.is_empty() {
+                // Connection closed
+                return Ok(Some(raw_request));
+            }
+            let seen = raw_request.len();
+            raw_request.extend(chunk);
+
+            match self.protocol.negotiate(&raw_request, seen) {
+                Negotiation::Continue => {}
+                Negotiation::Reply(reply) => self.write(conn, &reply, bytes).await?,
+                Negotiation::Refuse(reply) => {
+                    self.write(conn, &reply, bytes).await?;
+                    info!("Connection refused by protocol");
+                    return Ok(None);
+                }
+            }
+            if self.protocol.request_complete(&raw_request) {
+                return Ok(Some(raw_request));
+            }
+            if raw_request.len() >= MAX_BUFFERED_REQUEST_SIZE {
+                warn!(
+                    len = raw_request.len(),
+                    "Request too large, parsing what has been read"
+                );
+                return Ok(Some(raw_request));
+            }
+        }
+    }
+}
+
+/// Writes `data` to `conn`, failing with [`TransportError::WriteTimeout`] if it takes longer than
+/// `limit`
+async fn write_within<T: Transport>(
+    transport: &T,
+    conn: &mut T::Connection,
+    data: &[u8],
+    limit: Option<Duration>,
+) -> Result<()> {
+    let write = transport
+        .write(conn, data)
+        .instrument(info_span!("write_connection"));
+    match limit {
+        Some(limit) => timeout(limit, write).await.map_err(|_| {
+            warn!(timeout = "write", ?limit, "Connection timed out");
+            TransportError::WriteTimeout(limit)
+        })??,
+        None => write.await?,
+    }
+    Ok(())
+}
+
+/// Accepts a connection, reaping connection tasks that finish in the meantime
+async fn accept_reaping<T: Transport>(
+    transport: &T,
+    connections: &mut JoinSet<()>,
+) -> std::result::Result<T::Connection, TransportError> {
+    let accept = transport.accept();
+    pin!(accept);
+    loop {
+        tokio::select! {
+            biased;
+            result = &mut accept => return result,
+            Some(result) = connections.join_next() => reap(result),
+        }
+    }
+}
+
+async fn wait_for_connections(connections: &mut JoinSet<()>) {
+    while let Some(result) = connections.join_next().await {
+        reap(result);
+    }
+}
+
+fn reap(result: std::result::Result<(), tokio::task::JoinError>) {
+    if let Err(err) = result {
+        if err.is_panic() {
+            error!(?err, "Connection task panicked");
+        }
+    }
+}
+
+/// Whether an error from [`Transport::accept`] only affects a single connection or is caused by
+/// temporary resource exhaustion, so the server should keep accepting
+fn is_transient(err: &TransportError) -> bool {
+    let TransportError::Io(err) = err else {
+        return false;
+    };
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        if [
+            libc::EMFILE,
+            libc::ENFILE,
+            libc::ENOBUFS,
+            libc::ENOMEM,
+            libc::EPROTO,
+            libc::EPERM,
+        ]
+        .contains(&code)
+        {
+            return true;
+        }
+    }
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::OutOfMemory
+    )
 }
 
 macro_rules! http_method {
@@ -267,3 +712,260 @@ where
     http_method!(patch, PATCH);
     // TODO?: files
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        transport::{MemoryClient, MemoryTransport},
+    };
+
+    /// Fails to accept with an aborted connection a few times before each connection
+    struct FlakyTransport {
+        inner: MemoryTransport,
+        failures: AtomicUsize,
+    }
+
+    impl Transport for FlakyTransport {
+        type Addr = ();
+
+        type Connection = <MemoryTransport as Transport>::Connection;
+
+        async fn bind(&mut self, local_addr: ()) -> crate::transport::TransportResult<()> {
+            self.inner.bind(local_addr).await
+        }
+
+        async fn accept(&self) -> crate::transport::TransportResult<Self::Connection> {
+            if self.failures.fetch_add(1, Relaxed) % 4 != 3 {
+                return Err(std::io::Error::from(ErrorKind::ConnectionAborted).into());
+            }
+            self.inner.accept().await
+        }
+
+        async fn read(
+            &self,
+            conn: &mut Self::Connection,
+        ) -> crate::transport::TransportResult<Vec<u8>> {
+            self.inner.read(conn).await
+        }
+
+        async fn write(
+            &self,
+            conn: &mut Self::Connection,
+            response: &[u8],
+        ) -> crate::transport::TransportResult<()> {
+            self.inner.write(conn, response).await
+        }
+
+        async fn shutdown_conn(
+            &self,
+            conn: Self::Connection,
+        ) -> crate::transport::TransportResult<()> {
+            self.inner.shutdown_conn(conn).await
+        }
+    }
+
+    async fn hello(_req: HttpRequest) -> Result<HttpResponse> {
+        Ok(HttpResponse::Ok().text("Hello"))
+    }
+
+    #[tokio::test]
+    async fn retries_transient_accept_errors() {
+        let (inner, client) = MemoryTransport::new();
+        let transport = FlakyTransport {
+            inner,
+            failures: AtomicUsize::new(0),
+        };
+        let server = tokio::spawn(
+            YarsServer::new(transport, HttpProtocol)
+                .get("/", hello)
+                .listen(()),
+        );
+
+        for _ in 0..2 {
+            let response = client.send(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            assert!(response.ends_with(b"Hello"));
+        }
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn classifies_accept_errors() {
+        assert!(is_transient(
+            &std::io::Error::from(ErrorKind::ConnectionAborted).into()
+        ));
+        #[cfg(unix)]
+        assert!(is_transient(
+            &std::io::Error::from_raw_os_error(libc::EMFILE).into()
+        ));
+        assert!(!is_transient(&TransportError::Tcp("not bound".into())));
+        assert!(!is_transient(&TransportError::Closed));
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_connections() {
+        static OPEN: AtomicUsize = AtomicUsize::new(0);
+        static MAX_OPEN: AtomicUsize = AtomicUsize::new(0);
+
+        let (transport, client) = MemoryTransport::new();
+        let server = YarsServer::new(transport, HttpProtocol)
+            .max_connections(2)
+            .get("/", async |_req: HttpRequest| -> Result<_> {
+                let open = OPEN.fetch_add(1, Relaxed) + 1;
+                MAX_OPEN.fetch_max(open, Relaxed);
+                sleep(Duration::from_millis(50)).await;
+                OPEN.fetch_sub(1, Relaxed);
+                Ok(HttpResponse::Ok().text("Hello"))
+            });
+        let server = tokio::spawn(server.listen(()));
+
+        async fn send(client: MemoryClient) -> Vec<u8> {
+            client.send(b"GET / HTTP/1.1\r\n\r\n").await.unwrap()
+        }
+        let requests: Vec<_> = (0..6).map(|_| tokio::spawn(send(client.clone()))).collect();
+        for request in requests {
+            assert!(request.await.unwrap().ends_with(b"Hello"));
+        }
+        assert_eq!(MAX_OPEN.load(Relaxed), 2);
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn attaches_connection_info_to_requests() {
+        let (transport, client) = MemoryTransport::new();
+        let server = YarsServer::new(transport, HttpProtocol).get(
+            "/",
+            async |req: HttpRequest| -> Result<_> {
+                let info = req.extensions.get::<ConnectionInfo>().unwrap();
+                assert_eq!((info.peer_addr, info.local_addr), (None, None));
+                assert_eq!(info.transport, "memory");
+                assert!(info.accepted_at <= SystemTime::now());
+                Ok(HttpResponse::Ok().text(info.id.to_string()))
+            },
+        );
+        let server = tokio::spawn(server.listen(()));
+
+        for id in 0..2 {
+            let response = client.send(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            assert!(response.ends_with(id.to_string().as_bytes()));
+        }
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    /// Accepts a connection from `client` and handles it, returning the result
+    async fn handle_one(
+        server: YarsServer<MemoryTransport, HttpProtocol>,
+        client: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        tokio::spawn(client);
+        let mut conn = server.transport.accept().await.unwrap();
+        server
+            .handle_connection(&mut conn, 0, SystemTime::now(), &mut ByteCount::default())
+            .await
+    }
+
+    fn timeout_server() -> (YarsServer<MemoryTransport, HttpProtocol>, MemoryClient) {
+        let (transport, client) = MemoryTransport::new();
+        let server = YarsServer::new(transport, HttpProtocol)
+            .first_byte_timeout(Duration::from_secs(1))
+            .request_timeout(Duration::from_secs(10))
+            .idle_timeout(Duration::from_secs(3))
+            .write_timeout(Duration::from_secs(1))
+            .get("/", hello)
+            .get("/large", async |_req: HttpRequest| -> Result<_> {
+                Ok(HttpResponse::Ok().text("a".repeat(100 * 1024)))
+            });
+        (server, client)
+    }
+
+    /// Sends each part of a request after its delay, then reads the response unless `read` is
+    /// false, in which case the connection is held open without reading
+    async fn send(client: MemoryClient, parts: Vec<(u64, &'static [u8])>, read: bool) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = client.connect().unwrap();
+        for (delay, part) in parts {
+            sleep(Duration::from_secs(delay)).await;
+            stream.write_all(part).await.unwrap();
+        }
+        match read {
+            true => drop(stream.read_to_end(&mut Vec::new()).await),
+            false => std::future::pending().await,
+        }
+    }
+
+    fn timed_out(result: Result<()>) -> Option<TransportError> {
+        match result {
+            Err(crate::Error::Transport(err)) if err.is_timeout() => Some(err),
+            _ => None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_requests_split_across_reads() {
+        let (server, client) = timeout_server();
+        let parts = vec![(0, &b"GET / HT"[..]), (2, b"TP/1.1\r\n"), (2, b"\r\n")];
+        let result = handle_one(server, send(client, parts, true)).await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_without_first_bytes() {
+        let (server, client) = timeout_server();
+        let parts = vec![(2, &b"GET / HTTP/1.1\r\n\r\n"[..])];
+        let result = handle_one(server, send(client, parts, true)).await;
+        assert!(matches!(
+            timed_out(result),
+            Some(TransportError::FirstByteTimeout(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_idle_connections() {
+        let (server, client) = timeout_server();
+        let parts = vec![(0, &b"GET / HT"[..]), (4, b"TP/1.1\r\n\r\n")];
+        let result = handle_one(server, send(client, parts, true)).await;
+        assert!(matches!(
+            timed_out(result),
+            Some(TransportError::IdleTimeout(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_slow_requests() {
+        let (server, client) = timeout_server();
+        // Never idle for long, but takes too long overall
+        let parts = vec![
+            (0, &b"GET"[..]),
+            (2, b" /"),
+            (2, b" HTTP/1.1"),
+            (2, b"\r\n"),
+            (2, b"X: y\r\n"),
+            (2, b"\r\n"),
+        ];
+        let result = handle_one(server, send(client, parts, true)).await;
+        assert!(matches!(
+            timed_out(result),
+            Some(TransportError::RequestTimeout(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_writes() {
+        let (server, client) = timeout_server();
+        // The response is larger than the in-memory connection's buffer
+        let parts = vec![(0, &b"GET /large HTTP/1.1\r\n\r\n"[..])];
+        let result = handle_one(server, send(client, parts, false)).await;
+        assert!(matches!(
+            timed_out(result),
+            Some(TransportError::WriteTimeout(_))
+        ));
+    }
+}
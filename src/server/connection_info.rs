@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// Information about the connection a request was read from, attached to every request as an
+/// extension by the server.
+///
+/// Only protocols that override
+/// [`Protocol::attach_extensions`][crate::protocol::Protocol::attach_extensions] pass it on to
+/// handlers, e.g. [`HttpProtocol`][crate::protocol::HttpProtocol] and
+/// [`Socks5Protocol`][crate::protocol::Socks5Protocol]. The default implementation discards it.
+///
+/// ```rust
+/// use yars::{http::{HttpRequest, HttpResponse}, ConnectionInfo};
+///
+/// async fn whoami(req: HttpRequest) -> yars::Result<HttpResponse> {
+///     let peer = req
+///         .extensions
+///         .get::<ConnectionInfo>()
+///         .and_then(|info| info.peer_addr);
+///     Ok(HttpResponse::Ok().text(format!("{peer:?}")))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// ID of the connection, which is also recorded on its tracing span
+    pub id: usize,
+    /// Address of the peer, see [`Transport::peer_addr`][crate::transport::Transport::peer_addr]
+    pub peer_addr: Option<SocketAddr>,
+    /// Address the connection was accepted on, see
+    /// [`Transport::conn_local_addr`][crate::transport::Transport::conn_local_addr]
+    pub local_addr: Option<SocketAddr>,
+    /// When the connection was accepted
+    pub accepted_at: SystemTime,
+    /// Kind of transport the connection was accepted by, e.g. `"tcp"`, see
+    /// [`Transport::kind`][crate::transport::Transport::kind]
+    pub transport: &'static str,
+}
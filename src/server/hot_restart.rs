@@ -0,0 +1,175 @@
+//! Hot restarts, where a running server hands its listening sockets over to a new instance of
+//! the current executable, then stops accepting connections and exits once open connections have
+//! finished.
+
+use std::env;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command};
+
+use socket2::SockRef;
+use tokio::{
+    io::AsyncReadExt,
+    signal::unix::{signal, SignalKind},
+};
+use tracing::{error, info};
+
+use crate::{
+    transport::{
+        listen_fds::{HANDOFF_FDS_ENV, HANDOFF_PARENT_ENV, HANDOFF_READY_ENV},
+        Transport,
+    },
+    Result, TransportError,
+};
+
+/// Waits for `kind` of signal, then hands the transport's listeners over to a new process.
+///
+/// Resolves with the PID of the new process once it is listening. If it fails to start, the
+/// error is logged and the next signal is waited for.
+pub(super) async fn hand_over(kind: SignalKind, transport: &impl Transport) -> Result<u32> {
+    let mut signal = signal(kind)?;
+
+    loop {
+        if signal.recv().await.is_none() {
+            // No more signals can be received
+            return std::future::pending().await;
+        }
+
+        info!("Received hot restart signal, starting new process");
+        match spawn_successor(&transport.listeners()).await {
+            Ok(pid) => return Ok(pid),
+            Err(err) => error!(?err, "Hot restart failed, continuing to serve"),
+        }
+    }
+}
+
+fn handoff_error(message: impl Into<String>) -> crate::Error {
+    TransportError::Generic(format!("Hot restart: {}", message.into())).into()
+}
+
+/// Starts the current executable with the same arguments, passing it `listeners`, and waits
+/// until it is listening on them
+async fn spawn_successor(listeners: &[BorrowedFd<'_>]) -> Result<u32> {
+    if listeners.is_empty() {
+        return Err(handoff_error("transport has no listeners to hand over"));
+    }
+
+    // The new process writes to this once it's ready, or closes it if it exits before then
+    let (ready, child_ready) = UnixStream::pair()?;
+
+    let fds: Vec<RawFd> = listeners.iter().map(AsRawFd::as_raw_fd).collect();
+    let fd_list: Vec<String> = fds.iter().map(ToString::to_string).collect();
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(HANDOFF_FDS_ENV, fd_list.join(","))
+        .env(HANDOFF_PARENT_ENV, process::id().to_string())
+        .env(HANDOFF_READY_ENV, child_ready.as_raw_fd().to_string())
+        // Sockets passed by systemd are handed over as ours, and would no longer be valid
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES");
+
+    let inherited: Vec<RawFd> = fds.into_iter().chain([child_ready.as_raw_fd()]).collect();
+    // SAFETY: only calls `fcntl`, which is async-signal-safe, and doesn't allocate
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherited {
+                let fd = BorrowedFd::borrow_raw(fd);
+                SockRef::from(&fd).set_cloexec(false)?;
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    drop(child_ready);
+
+    let pid = child.id();
+    info!(pid, "Started new process, waiting for it to take over");
+
+    ready.set_nonblocking(true)?;
+    let mut ready = tokio::net::UnixStream::from_std(ready)?;
+    match ready.read(&mut [0; 1]).await {
+        Ok(1) => Ok(pid),
+        result => {
+            // Don't leave a zombie process behind
+            let _ = child.kill();
+            tokio::task::spawn_blocking(move || child.wait());
+            Err(handoff_error(format!(
+                "new process {pid} exited before it was ready ({result:?})"
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+        test_util::{child_addr, reserve_port, send_signal, spawn_child, wait_until},
+        transport::TcpTransport,
+        YarsServer,
+    };
+
+    const CHILD: &str = "server::hot_restart::tests::child";
+
+    /// Sends a request, returning the PID of the process that served it
+    fn request_pid(port: u16) -> u32 {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.rsplit("\r\n").next().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn hands_listener_over_to_new_process() {
+        let socket = reserve_port();
+        socket.listen(128).unwrap();
+        let port = socket.local_addr().unwrap().as_socket().unwrap().port();
+
+        let mut child = spawn_child(CHILD, socket).spawn().unwrap();
+        assert_eq!(request_pid(port), child.id());
+
+        send_signal("-USR2", child.id());
+        wait_until("old process exiting", || {
+            child.try_wait().unwrap().is_some()
+        });
+        assert!(child.wait().unwrap().success());
+
+        // Served by the new process, on the same socket
+        let new_pid = request_pid(port);
+        assert_ne!(new_pid, child.id());
+
+        send_signal("-INT", new_pid);
+        wait_until("new process exiting", || {
+            TcpStream::connect(("127.0.0.1", port)).is_err()
+        });
+    }
+
+    /// Only does anything when spawned by [hands_listener_over_to_new_process], or by the
+    /// process it spawned
+    #[tokio::test]
+    async fn child() {
+        let Some(addr) = child_addr(CHILD) else {
+            return;
+        };
+
+        YarsServer::new(TcpTransport::new(), HttpProtocol)
+            .hot_restart_on(SignalKind::user_defined2())
+            .get("/", async |_req: HttpRequest| -> crate::Result<_> {
+                Ok(HttpResponse::Ok().text(process::id().to_string()))
+            })
+            .listen(addr)
+            .await
+            .unwrap();
+    }
+}
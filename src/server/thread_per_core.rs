@@ -0,0 +1,266 @@
+//! Thread-per-core mode, where each thread runs its own current-thread runtime and its own
+//! server, listening on the same address with `SO_REUSEPORT`.
+//!
+//! Nothing is shared between the threads, so a connection is accepted, served and woken up on the
+//! same thread. Each thread counts its own connections, and connection IDs are strided by thread
+//! so that they are still unique.
+
+use std::num::NonZeroUsize;
+use std::thread;
+
+use tokio::runtime;
+use tracing::{error, info, info_span};
+
+use super::ConnIds;
+use crate::{protocol::Protocol, transport::Transport, Result, YarsServer};
+
+/// Runs a separate [YarsServer] on each core, each on its own single-threaded tokio runtime.
+///
+/// Each thread builds its server with `make_server`, so gets its own router and transport. The
+/// transports are bound with `SO_REUSEPORT` (see [`Transport::set_reuse_port`]), so that the
+/// kernel balances connections between the threads.
+///
+/// # Example Usage
+/// ```rust,no_run
+/// use yars::{
+///     http::{HttpRequest, HttpResponse},
+///     ThreadPerCore, YarsServer,
+/// };
+///
+/// async fn hello(_req: HttpRequest) -> yars::Result<HttpResponse> {
+///     Ok(HttpResponse::Ok().text("Hello, World!"))
+/// }
+///
+/// // Note: not `#[tokio::main]`, as each thread creates its own runtime
+/// fn main() -> yars::Result<()> {
+///     ThreadPerCore::new(|| YarsServer::default_server().get("/", hello)).listen("127.0.0.1:8000")
+/// }
+/// ```
+pub struct ThreadPerCore<F> {
+    make_server: F,
+    threads: usize,
+    pin_threads: bool,
+}
+
+impl<F, T, P> ThreadPerCore<F>
+where
+    F: Fn() -> YarsServer<T, P> + Sync,
+    T: Transport,
+    P: Protocol,
+{
+    /// Runs one server per available core, each built by `make_server`
+    pub fn new(make_server: F) -> Self {
+        Self {
+            make_server,
+            threads: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            pin_threads: true,
+        }
+    }
+
+    /// Sets the number of threads, instead of one per available core
+    pub fn threads(mut self, count: usize) -> Self {
+        self.threads = count.max(1);
+        self
+    }
+
+    /// Whether to pin each thread to its own core, so that the OS doesn't move it between cores.
+    /// Defaults to `true`. Only supported on Linux, ignored elsewhere.
+    pub fn pin_threads(mut self, pin: bool) -> Self {
+        self.pin_threads = pin;
+        self
+    }
+
+    /// Starts a server on each thread, all listening on `addr`, and blocks until they have all
+    /// stopped (e.g. on Ctrl-C).
+    ///
+    /// Must not be called from within a tokio runtime.
+    pub fn listen<A>(self, addr: A) -> Result<()>
+    where
+        A: Into<T::Addr> + Clone + Send,
+    {
+        info!(threads = self.threads, "Starting thread per core servers");
+        let cores = if self.pin_threads {
+            cores()
+        } else {
+            Vec::new()
+        };
+
+        let this = &self;
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..self.threads)
+                .map(|index| {
+                    let addr = addr.clone();
+                    let core = (!cores.is_empty()).then(|| cores[index % cores.len()]);
+                    thread::Builder::new()
+                        .name(format!("yars-core-{index}"))
+                        .spawn_scoped(scope, move || this.run_thread(index, core, addr))
+                })
+                .collect::<std::io::Result<_>>()?;
+
+            let mut result = Ok(());
+            for handle in handles {
+                let thread_result = handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                if let Err(err) = thread_result {
+                    result = Err(err);
+                }
+            }
+            info!("All threads stopped");
+            result
+        })
+    }
+
+    fn run_thread(
+        &self,
+        index: usize,
+        core: Option<usize>,
+        addr: impl Into<T::Addr>,
+    ) -> Result<()> {
+        let _entered = info_span!("thread", index).entered();
+
+        if let Some(core) = core {
+            if let Err(err) = pin_to_core(core) {
+                error!(?err, core, "Failed to pin thread to core");
+            }
+        }
+
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let mut server = (self.make_server)();
+        server.conn_ids = ConnIds {
+            offset: index,
+            stride: self.threads,
+        };
+        server.transport.set_reuse_port(true)?;
+
+        runtime.block_on(server.listen(addr)).inspect_err(|err| {
+            error!(?err, "Server stopped with an error");
+        })
+    }
+}
+
+/// The cores this process is allowed to run on
+#[cfg(target_os = "linux")]
+fn cores() -> Vec<usize> {
+    // SAFETY: `cpu_set_t` is plain data, which is valid when zeroed
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `set` is a valid `cpu_set_t` of the given size
+    if unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) } == -1 {
+        return Vec::new();
+    }
+    (0..libc::CPU_SETSIZE as usize)
+        // SAFETY: `core` is less than `CPU_SETSIZE`
+        .filter(|&core| unsafe { libc::CPU_ISSET(core, &set) })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cores() -> Vec<usize> {
+    Vec::new()
+}
+
+/// Pins the current thread to `core`
+#[cfg(target_os = "linux")]
+fn pin_to_core(core: usize) -> std::io::Result<()> {
+    // SAFETY: `cpu_set_t` is plain data, which is valid when zeroed
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `core` comes from `cores`, so is less than `CPU_SETSIZE`
+    unsafe { libc::CPU_SET(core, &mut set) };
+    // SAFETY: `set` is a valid `cpu_set_t` of the given size
+    if unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_core(_core: usize) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+        test_util::{child_addr, reserve_port, send_signal, spawn_child, wait_until},
+        transport::TcpTransport,
+        ConnectionInfo,
+    };
+
+    const CHILD: &str = "server::thread_per_core::tests::child";
+
+    /// Sends a request, returning the name of the thread that served it and the connection ID
+    fn request_thread(port: u16) -> Option<(String, usize)> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        response
+            .split_once("\r\n\r\n")
+            .and_then(|(_, body)| body.split_once(' '))
+            .map(|(thread, id)| (thread.to_string(), id.parse().unwrap()))
+    }
+
+    #[test]
+    fn serves_on_every_thread() {
+        // One thread adopts it, and the other binds alongside it with `SO_REUSEPORT`
+        let socket = reserve_port();
+        socket.listen(128).unwrap();
+        let port = socket.local_addr().unwrap().as_socket().unwrap().port();
+
+        // Run in a separate process, so that it can be stopped with SIGINT
+        let mut child = spawn_child(CHILD, socket).spawn().unwrap();
+
+        let mut threads = HashSet::new();
+        let mut ids = HashSet::new();
+        wait_until("both threads serving", || {
+            if let Some((thread, id)) = request_thread(port) {
+                // Connection IDs are unique across threads, as each thread hands out every other
+                // ID
+                assert!(ids.insert(id), "{id} handed out twice");
+                assert_eq!(thread, format!("yars-core-{}", id % 2));
+                threads.insert(thread);
+            }
+            threads.len() == 2
+        });
+        assert_eq!(
+            threads,
+            HashSet::from(["yars-core-0".to_string(), "yars-core-1".to_string()])
+        );
+
+        send_signal("-INT", child.id());
+        assert!(child.wait().unwrap().success());
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    /// Only does anything when spawned by [serves_on_every_thread]
+    #[test]
+    fn child() {
+        let Some(addr) = child_addr(CHILD) else {
+            return;
+        };
+
+        ThreadPerCore::new(|| {
+            YarsServer::new(TcpTransport::new(), HttpProtocol).get(
+                "/",
+                async |req: HttpRequest| -> crate::Result<_> {
+                    let thread = std::thread::current();
+                    let id = req.extensions.get::<ConnectionInfo>().unwrap().id;
+                    Ok(HttpResponse::Ok()
+                        .text(format!("{} {id}", thread.name().unwrap_or_default())))
+                },
+            )
+        })
+        .threads(2)
+        .listen(addr)
+        .unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use tracing::{info_span, trace, Instrument};
+
+use super::{write_within, Timeouts};
+use crate::{
+    transport::{ByteCount, Transport},
+    Result,
+};
+
+/// A connection that a protocol has taken over once its response has been written, see
+/// [`Protocol::take_over`][crate::protocol::Protocol::take_over].
+///
+/// Bytes read and written are counted towards the connection, and writes are subject to the
+/// server's [`write_timeout`][crate::YarsServer::write_timeout], as for requests and responses.
+pub struct Tunnel<'a, T: Transport> {
+    transport: &'a T,
+    conn: &'a mut T::Connection,
+    bytes: &'a mut ByteCount,
+    timeouts: Timeouts,
+}
+
+impl<'a, T: Transport> Tunnel<'a, T> {
+    pub(super) fn new(
+        transport: &'a T,
+        conn: &'a mut T::Connection,
+        bytes: &'a mut ByteCount,
+        timeouts: Timeouts,
+    ) -> Self {
+        Self {
+            transport,
+            conn,
+            bytes,
+            timeouts,
+        }
+    }
+
+    /// Reads the next bytes from the client, see [`Transport::read`]. Like it, this is cancel
+    /// safe, and an empty read means the client has closed its side of the connection.
+    pub async fn read(&mut self) -> Result<Vec<u8>> {
+        trace!("Attempting to read from tunnel");
+        let chunk = self
+            .transport
+            .read(self.conn)
+            .instrument(info_span!("read_connection"))
+            .await?;
+        self.bytes.read += chunk.len() as u64;
+        Ok(chunk)
+    }
+
+    /// Writes `data` to the client within the write timeout
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        trace!("Attempting to write to tunnel");
+        write_within(self.transport, self.conn, data, self.timeouts.write).await?;
+        self.bytes.written += data.len() as u64;
+        Ok(())
+    }
+
+    /// How long the tunnel may go without bytes in either direction before it should be closed
+    /// with [`TransportError::IdleTimeout`][crate::TransportError::IdleTimeout], see
+    /// [`idle_timeout`][crate::YarsServer::idle_timeout]
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.timeouts.idle
+    }
+}
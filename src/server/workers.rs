@@ -0,0 +1,290 @@
+//! Pre-fork worker mode, where a supervisor process runs the server in several worker processes
+//! that share the same address with `SO_REUSEPORT`, and restarts any worker that crashes.
+//!
+//! Workers are started by running the current executable again with the same arguments, rather
+//! than by forking, as forking a process with a multi-threaded async runtime isn't safe.
+
+use std::env;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use tokio::{
+    process::{Child, Command},
+    runtime::Handle,
+    signal,
+    sync::{oneshot, watch},
+    task::JoinSet,
+    time::sleep,
+};
+use tracing::{error, info, warn};
+
+use crate::Result;
+
+/// Index of the worker, set for each worker process
+const WORKER_ID_ENV: &str = "YARS_WORKER_ID";
+/// PID of the supervisor, which must be the worker's parent
+const WORKER_SUPERVISOR_ENV: &str = "YARS_WORKER_SUPERVISOR";
+
+/// How long to wait before restarting a crashed worker, so a worker that crashes on startup
+/// doesn't use up all the CPU
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+/// The index of this worker, if this process was started by a supervisor
+pub(super) fn worker_id() -> Option<usize> {
+    let supervisor: u32 = env::var(WORKER_SUPERVISOR_ENV).ok()?.parse().ok()?;
+    if supervisor != std::os::unix::process::parent_id() {
+        // e.g. a process started by a worker, which inherited its environment
+        return None;
+    }
+    env::var(WORKER_ID_ENV).ok()?.parse().ok()
+}
+
+/// Runs `count` workers until SIGINT is received, or until they have all exited successfully.
+/// Workers that exit unsuccessfully (e.g. from a panic, or being killed for using too much
+/// memory) are restarted.
+pub(super) async fn supervise(count: usize) -> Result<()> {
+    info!(count, "Starting workers");
+
+    let spawner = Spawner::start()?;
+    let (shutdown, shutdown_rx) = watch::channel(());
+    let mut workers = JoinSet::new();
+    for id in 0..count {
+        workers.spawn(run_worker(id, spawner.clone(), shutdown_rx.clone()));
+    }
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {
+            info!("Received SIGINT, stopping workers");
+            let _ = shutdown.send(());
+        }
+        _ = async { while workers.join_next().await.is_some() {} } => {}
+    }
+
+    let mut result = Ok(());
+    while let Some(worker_result) = workers.join_next().await {
+        if let Err(err) = worker_result
+            .map_err(std::io::Error::from)
+            .and_then(|result| result)
+        {
+            error!(?err, "Error running worker");
+            result = Err(err.into());
+        }
+    }
+    info!("All workers stopped");
+    result
+}
+
+/// Runs the worker with index `id`, restarting it whenever it crashes
+async fn run_worker(
+    id: usize,
+    spawner: Spawner,
+    mut shutdown: watch::Receiver<()>,
+) -> std::io::Result<()> {
+    loop {
+        let mut child = spawner.spawn(worker_command(id)?).await?;
+        let pid = child.id();
+        info!(worker = id, pid, "Started worker");
+
+        let status = tokio::select! {
+            status = child.wait() => status?,
+            _ = shutdown.changed() => {
+                if let Some(pid) = child.id() {
+                    // Let the worker shut down gracefully, as it would on Ctrl-C
+                    // SAFETY: `kill` has no memory safety requirements
+                    unsafe { libc::kill(pid as libc::pid_t, libc::SIGINT) };
+                }
+                child.wait().await?;
+                return Ok(());
+            }
+        };
+
+        if status.success() {
+            info!(worker = id, pid, "Worker exited");
+            return Ok(());
+        }
+
+        warn!(worker = id, pid, %status, "Worker crashed, restarting");
+        tokio::select! {
+            _ = sleep(RESTART_DELAY) => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+}
+
+fn worker_command(id: usize) -> std::io::Result<Command> {
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(WORKER_ID_ENV, id.to_string())
+        .env(WORKER_SUPERVISOR_ENV, process::id().to_string());
+    stop_with_parent(&mut command);
+    Ok(command)
+}
+
+/// Stops the process spawned by `command` if the thread that spawns it exits, see [Spawner]
+fn stop_with_parent(command: &mut Command) {
+    // SAFETY: `prctl` is async-signal-safe
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGINT) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = command;
+}
+
+type SpawnRequest = (Command, oneshot::Sender<std::io::Result<Child>>);
+
+/// Spawns workers from a dedicated thread, which lives until the supervisor has stopped them.
+///
+/// The signal set by [stop_with_parent] is sent when the thread that spawned the worker exits,
+/// not the supervisor process, so that workers stop if the supervisor dies rather than running
+/// unsupervised. Spawning from runtime threads would also stop them whenever the runtime stops
+/// one of its threads, e.g. an idle blocking thread.
+#[derive(Clone)]
+struct Spawner {
+    requests: mpsc::Sender<SpawnRequest>,
+}
+
+impl Spawner {
+    /// Starts the thread, which spawns within the current runtime and stops once every
+    /// [Spawner] is dropped
+    fn start() -> std::io::Result<Self> {
+        let (requests, rx) = mpsc::channel::<SpawnRequest>();
+        let runtime = Handle::current();
+        thread::Builder::new()
+            .name("yars-spawner".into())
+            .spawn(move || {
+                let _entered = runtime.enter();
+                for (mut command, child) in rx {
+                    let _ = child.send(command.spawn());
+                }
+            })?;
+        Ok(Self { requests })
+    }
+
+    async fn spawn(&self, command: Command) -> std::io::Result<Child> {
+        let stopped = || std::io::Error::other("Worker spawner thread stopped");
+        let (child, rx) = oneshot::channel();
+        self.requests
+            .send((command, child))
+            .map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+        test_util::{child_addr, reserve_port, send_signal, spawn_child, wait_until},
+        transport::TcpTransport,
+        YarsServer,
+    };
+
+    const CHILD: &str = "server::workers::tests::child";
+
+    /// Sends a request to `path`, returning the response body
+    fn request(port: u16, path: &str) -> Option<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+        write!(stream, "GET {path} HTTP/1.1\r\n\r\n").ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+    }
+
+    #[test]
+    fn restarts_crashed_workers() {
+        // Not listening, so that it only reserves the port for the workers to bind to
+        let socket = reserve_port();
+        let port = socket.local_addr().unwrap().as_socket().unwrap().port();
+
+        let mut supervisor = spawn_child(CHILD, socket).spawn().unwrap();
+
+        // Requests are served by the workers, not the supervisor
+        let mut pids = HashSet::new();
+        wait_until("both workers serving", || {
+            pids.extend(request(port, "/"));
+            pids.len() == 2
+        });
+        assert!(!pids.contains(&supervisor.id().to_string()));
+
+        // Crash a worker once, as crashing on every poll could keep killing its replacement
+        assert_eq!(request(port, "/crash"), None);
+        wait_until("crashed worker restarted", || {
+            request(port, "/").is_some_and(|pid| !pids.contains(&pid))
+        });
+
+        send_signal("-INT", supervisor.id());
+        assert!(supervisor.wait().unwrap().success());
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    /// Workers outlive the threads that ask for them to be spawned, but not the spawner
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn workers_stop_with_spawner_thread() {
+        let sleeper = || {
+            let mut command = Command::new("sleep");
+            command.arg("10");
+            stop_with_parent(&mut command);
+            command
+        };
+        let spawner = Spawner::start().unwrap();
+
+        let runtime = Handle::current();
+        let mut child = thread::spawn({
+            let spawner = spawner.clone();
+            move || runtime.block_on(spawner.spawn(sleeper()))
+        })
+        .join()
+        .unwrap()
+        .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert!(child.try_wait().unwrap().is_none());
+
+        // Only the spawner thread's exit stops it
+        drop(spawner);
+        let status = tokio::time::timeout(Duration::from_secs(10), child.wait())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!status.success());
+    }
+
+    /// Only does anything when spawned by [restarts_crashed_workers], as either the supervisor
+    /// or a worker
+    #[tokio::test]
+    async fn child() {
+        let Some(addr) = child_addr(CHILD) else {
+            return;
+        };
+
+        YarsServer::new(TcpTransport::new(), HttpProtocol)
+            .workers(2)
+            .get("/", async |_req: HttpRequest| -> crate::Result<_> {
+                Ok(HttpResponse::Ok().text(process::id().to_string()))
+            })
+            .get(
+                "/crash",
+                async |_req: HttpRequest| -> crate::Result<HttpResponse> { process::abort() },
+            )
+            .listen(addr)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+//! SOCKS5 requests and responses, see [Socks5Protocol][crate::protocol::Socks5Protocol]
+//!
+//! <https://datatracker.ietf.org/doc/html/rfc1928>
+
+pub(crate) mod parser;
+mod request;
+mod response;
+
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+pub use request::{Command, Socks5Request, TargetAddr};
+pub use response::{ReplyCode, Socks5Response};
+
+/// Version of SOCKS that is supported
+pub(crate) const VERSION: u8 = 5;
+/// Version of the username/password authentication subnegotiation
+///
+/// <https://datatracker.ietf.org/doc/html/rfc1929>
+pub(crate) const AUTH_VERSION: u8 = 1;
+
+/// Authentication methods the client can offer
+pub(crate) mod method {
+    pub(crate) const NO_AUTH: u8 = 0x00;
+    pub(crate) const USERNAME_PASSWORD: u8 = 0x02;
+    pub(crate) const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+}
+
+/// Handler that connects to the target of a [`Command::Connect`] request, so that the connection
+/// is relayed to it.
+///
+/// Failing to connect is replied to the client, rather than returned as an error.
+pub async fn connect(req: Socks5Request) -> crate::Result<Socks5Response> {
+    let upstream = match &req.target {
+        TargetAddr::Ip(addr) => TcpStream::connect(addr).await,
+        TargetAddr::Domain(host, port) => TcpStream::connect((host.as_str(), *port)).await,
+    };
+    match upstream {
+        Ok(upstream) => {
+            info!(target = %req.target, "Relaying to target");
+            Ok(Socks5Response::connected(upstream).pending(req.pending))
+        }
+        Err(err) => {
+            debug!(target = %req.target, %err, "Failed to connect to target");
+            Ok(ReplyCode::from(&err).into())
+        }
+    }
+}
+
+/// Handler that replies that the command isn't supported, e.g. as the default handler for
+/// commands without a route
+pub async fn not_supported(req: Socks5Request) -> crate::Result<Socks5Response> {
+    debug!(command = %req.command, "Command not supported");
+    Ok(ReplyCode::CommandNotSupported.into())
+}
@@ -0,0 +1,113 @@
+//! Uses the [`nom`] parser combinator library to parse the SOCKS5 handshake.
+//!
+//! Streaming parsers are used because the handshake arrives over multiple reads.
+//!
+//! <https://datatracker.ietf.org/doc/html/rfc1928>
+//! <https://datatracker.ietf.org/doc/html/rfc1929>
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+use nom::{
+    bytes::streaming::tag,
+    combinator::{map, map_opt, map_res},
+    error::{Error, ErrorKind},
+    multi::length_data,
+    number::streaming::{be_u128, be_u16, be_u32, be_u8},
+    IResult, Parser,
+};
+
+use super::{Command, TargetAddr, AUTH_VERSION, VERSION};
+
+/// A string prefixed by its length in a single byte
+fn short_string(input: &[u8]) -> IResult<&[u8], String> {
+    map_res(length_data(be_u8), |bytes| {
+        str::from_utf8(bytes).map(str::to_owned)
+    })
+    .parse(input)
+}
+
+/// VER NMETHODS METHODS, returning the authentication methods offered by the client
+pub(crate) fn greeting(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (input, _) = tag(&[VERSION][..]).parse(input)?;
+    length_data(be_u8).parse(input)
+}
+
+/// VER ULEN UNAME PLEN PASSWD, returning the username and password
+pub(crate) fn credentials(input: &[u8]) -> IResult<&[u8], (String, String)> {
+    let (input, _) = tag(&[AUTH_VERSION][..]).parse(input)?;
+    (short_string, short_string).parse(input)
+}
+
+/// ATYP DST.ADDR DST.PORT
+fn target_addr(input: &[u8]) -> IResult<&[u8], TargetAddr> {
+    let (input, address_type) = be_u8.parse(input)?;
+    match address_type {
+        0x01 => map((be_u32, be_u16), |(ip, port)| {
+            TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        })
+        .parse(input),
+        0x03 => map((short_string, be_u16), |(host, port)| {
+            TargetAddr::Domain(host, port)
+        })
+        .parse(input),
+        0x04 => map((be_u128, be_u16), |(ip, port)| {
+            TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        })
+        .parse(input),
+        _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Switch))),
+    }
+}
+
+/// VER CMD RSV ATYP DST.ADDR DST.PORT
+pub(crate) fn request(input: &[u8]) -> IResult<&[u8], (Command, TargetAddr)> {
+    let (input, _) = tag(&[VERSION][..]).parse(input)?;
+    let (input, command) = map_opt(be_u8, Command::from_code).parse(input)?;
+    let (input, _) = tag(&[0x00][..]).parse(input)?;
+    let (input, target) = target_addr(input)?;
+    Ok((input, (command, target)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_greeting() {
+        assert_eq!(greeting(&[5, 2, 0, 2]), Ok((&[][..], &[0, 2][..])));
+        assert!(matches!(greeting(&[5, 2, 0]), Err(nom::Err::Incomplete(_))));
+        assert!(matches!(greeting(&[4, 1, 0]), Err(nom::Err::Error(_))));
+    }
+
+    #[test]
+    fn parses_credentials() {
+        let (input, credentials) = credentials(b"\x01\x04user\x04pass").unwrap();
+        assert!(input.is_empty());
+        assert_eq!(credentials, ("user".into(), "pass".into()));
+    }
+
+    #[test]
+    fn parses_requests() {
+        let (_, (command, target)) = request(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+        assert_eq!(command, Command::Connect);
+        assert_eq!(target, TargetAddr::Ip("127.0.0.1:80".parse().unwrap()));
+
+        let (_, (command, target)) = request(b"\x05\x03\x00\x03\x0bexample.com\x01\xbb").unwrap();
+        assert_eq!(command, Command::UdpAssociate);
+        assert_eq!(target, TargetAddr::Domain("example.com".into(), 443));
+
+        let mut ipv6 = vec![5, 2, 0, 4];
+        ipv6.extend(Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend(8080u16.to_be_bytes());
+        let (_, (_, target)) = request(&ipv6).unwrap();
+        assert_eq!(target, TargetAddr::Ip("[::1]:8080".parse().unwrap()));
+
+        assert!(matches!(
+            request(&[5, 1, 0, 1, 127, 0]),
+            Err(nom::Err::Incomplete(_))
+        ));
+        // Unknown command and address type
+        assert!(request(&[5, 9, 0, 1, 127, 0, 0, 1, 0, 80]).is_err());
+        assert!(request(&[5, 1, 0, 2, 127, 0, 0, 1, 0, 80]).is_err());
+    }
+}
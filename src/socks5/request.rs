@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+
+use crate::Extensions;
+
+/// SOCKS5 request, sent by the client once it has authenticated
+///
+/// <https://datatracker.ietf.org/doc/html/rfc1928#section-4>
+#[derive(Debug)]
+pub struct Socks5Request {
+    pub command: Command,
+    /// Where the client wants to connect to
+    pub target: TargetAddr,
+    /// Username the client authenticated with, if authentication is required
+    pub username: Option<String>,
+    /// Bytes the client sent after the request without waiting for the reply, which are sent to
+    /// the target before relaying, see [`Socks5Response::pending`][super::Socks5Response::pending]
+    pub pending: Vec<u8>,
+    /// Connection-level information provided by the transport layer
+    pub extensions: Extensions,
+}
+
+/// SOCKS5 command, which requests are routed by
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+impl Command {
+    pub(crate) fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x01 => Some(Self::Connect),
+            0x02 => Some(Self::Bind),
+            0x03 => Some(Self::UdpAssociate),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connect => write!(f, "CONNECT"),
+            Self::Bind => write!(f, "BIND"),
+            Self::UdpAssociate => write!(f, "UDP ASSOCIATE"),
+        }
+    }
+}
+
+/// Address of the target of a request. Domain names are resolved by the server.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl std::fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{addr}"),
+            Self::Domain(host, port) => write!(f, "{host}:{port}"),
+        }
+    }
+}
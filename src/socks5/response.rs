@@ -0,0 +1,125 @@
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use tokio::net::TcpStream;
+
+use super::VERSION;
+
+/// SOCKS5 reply to a request. A successful reply to a [`Command::Connect`][super::Command]
+/// request carries the connection to the target, which the client is then relayed to.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc1928#section-6>
+#[derive(Debug)]
+pub struct Socks5Response {
+    pub(crate) code: ReplyCode,
+    /// Address the server uses to connect to the target
+    pub(crate) bound: Option<SocketAddr>,
+    pub(crate) upstream: Option<TcpStream>,
+    /// Sent to the upstream before relaying
+    pub(crate) pending: Vec<u8>,
+}
+
+/// Reply field of a SOCKS5 reply
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReplyCode {
+    Succeeded,
+    GeneralFailure,
+    /// Connection not allowed by ruleset
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+}
+
+impl Socks5Response {
+    /// Reply without relaying, e.g. to refuse the request
+    pub fn new(code: ReplyCode) -> Self {
+        Self {
+            code,
+            bound: None,
+            upstream: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Reply that the connection to the target succeeded, and relay the client to `upstream`
+    pub fn connected(upstream: TcpStream) -> Self {
+        Self {
+            code: ReplyCode::Succeeded,
+            bound: upstream.local_addr().ok(),
+            upstream: Some(upstream),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sends `pending` to the upstream before relaying, i.e. the [`Socks5Request::pending`]
+    /// bytes of the request, which would otherwise be lost
+    ///
+    /// [`Socks5Request::pending`]: super::Socks5Request::pending
+    pub fn pending(mut self, pending: Vec<u8>) -> Self {
+        self.pending = pending;
+        self
+    }
+
+    pub fn code(&self) -> ReplyCode {
+        self.code
+    }
+
+    /// VER REP RSV ATYP BND.ADDR BND.PORT
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let bound = self
+            .bound
+            .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+
+        let mut buf = vec![VERSION, self.code.code(), 0x00];
+        match bound {
+            SocketAddr::V4(addr) => {
+                buf.push(0x01);
+                buf.extend(addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                buf.push(0x04);
+                buf.extend(addr.ip().octets());
+            }
+        }
+        buf.extend(bound.port().to_be_bytes());
+        buf
+    }
+}
+
+impl From<ReplyCode> for Socks5Response {
+    fn from(code: ReplyCode) -> Self {
+        Self::new(code)
+    }
+}
+
+impl ReplyCode {
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Succeeded => 0x00,
+            Self::GeneralFailure => 0x01,
+            Self::NotAllowed => 0x02,
+            Self::NetworkUnreachable => 0x03,
+            Self::HostUnreachable => 0x04,
+            Self::ConnectionRefused => 0x05,
+            Self::TtlExpired => 0x06,
+            Self::CommandNotSupported => 0x07,
+            Self::AddressTypeNotSupported => 0x08,
+        }
+    }
+}
+
+/// The reply for failing to connect to the target
+impl From<&std::io::Error> for ReplyCode {
+    fn from(err: &std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            ErrorKind::NetworkUnreachable => Self::NetworkUnreachable,
+            ErrorKind::HostUnreachable | ErrorKind::TimedOut => Self::HostUnreachable,
+            _ => Self::GeneralFailure,
+        }
+    }
+}
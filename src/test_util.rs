@@ -0,0 +1,85 @@
+//! Helpers for tests that run a server in a child process, e.g. to stop it with a signal.
+//!
+//! The child runs a test of the same binary, which only does anything when spawned by
+//! [spawn_child]. It is passed a bound socket the way systemd would, so that the port stays
+//! reserved for the whole test rather than being freed for anything else to take.
+
+use std::env;
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Socket, Type};
+
+/// Environment variable telling the test binary which of its tests it has been spawned to run
+const CHILD_ENV: &str = "YARS_TEST_CHILD";
+/// Environment variable containing the address of the socket passed to the child
+const CHILD_ADDR_ENV: &str = "YARS_TEST_CHILD_ADDR";
+
+/// Binds a TCP socket to a free port on localhost, without listening on it.
+///
+/// It has `SO_REUSEPORT` set, so servers that bind with it (see
+/// [`Transport::set_reuse_port`][crate::transport::Transport::set_reuse_port]) can share the
+/// port. Servers that bind without it adopt the socket once it is listening and passed to them.
+pub(crate) fn reserve_port() -> Socket {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket.set_reuse_port(true).unwrap();
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+        .unwrap();
+    socket
+}
+
+/// Runs `test` of this test binary in a child process, passing it `socket` as its only socket
+/// activation socket (see [ListenFd][crate::transport::ListenFd]).
+///
+/// Returns the command to spawn, so that more environment variables can be set.
+pub(crate) fn spawn_child(test: &str, socket: Socket) -> Command {
+    let addr = socket.local_addr().unwrap().as_socket().unwrap();
+
+    // The shell moves the socket from stdin to fd 3, and `exec` keeps its PID
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(r#"exec 3<&0 0</dev/null; export LISTEN_PID=$$; exec "$0" "$@""#)
+        .arg(env::current_exe().unwrap())
+        .args(["--exact", test, "--nocapture"])
+        .env("LISTEN_FDS", "1")
+        .env(CHILD_ENV, test)
+        .env(CHILD_ADDR_ENV, addr.to_string())
+        .stdin(Stdio::from(OwnedFd::from(socket)));
+    command
+}
+
+/// The address of the socket passed to this process, if it has been spawned by [spawn_child] to
+/// run `test`. Tests run as children return early otherwise.
+///
+/// Processes started by the child inherit its environment, so also run `test`.
+pub(crate) fn child_addr(test: &str) -> Option<SocketAddr> {
+    if env::var(CHILD_ENV).ok()? != test {
+        return None;
+    }
+    env::var(CHILD_ADDR_ENV).ok()?.parse().ok()
+}
+
+/// Sends `signal` (e.g. `-INT`) to the process `pid`
+pub(crate) fn send_signal(signal: &str, pid: u32) {
+    let status = Command::new("kill")
+        .args([signal, &pid.to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// Polls `done` until it is true, failing the test after a few seconds
+pub(crate) fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "Timed out: {what}"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -3,28 +3,173 @@
 //! Allows user-defined protocols in transport layer.
 //!
 //! Supported protocols:
-//! - TCP
+//! - TCP, also with io_uring on Linux (requires the `io-uring` feature)
+//! - UDP
+//! - Unix domain sockets
+//! - In-memory, for tests
+//! - Stdio, for inetd-style serving of a single connection
+//! - TLS, on top of any stream-based transport (requires the `tls` feature)
+//! - PROXY protocol, on top of any stream-based transport
+//! - Chaos, injecting faults into any transport for testing
+//! - Admission, accepting connections by peer IP address on top of any transport
+//! - Throttled, limiting the bandwidth of any transport per connection and per IP address
+//! - Recording, capturing the traffic of any transport to replay against a server later
+//! - QUIC, with each stream as a connection (requires the `quic` feature)
+//!
+//! On Unix, TCP, UDP and Unix socket transports can also adopt sockets passed by systemd socket
+//! activation instead of binding their own, see [ListenFd].
+//!
+//! A server can listen on multiple addresses with [MultiTransport], or with multiple kinds of
+//! transport with [EitherTransport].
 
-use std::future::Future;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{Mutex, MutexGuard},
+};
 
-use tokio::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::os::fd::BorrowedFd;
 
+mod admission;
+mod chaos;
+mod cidr;
+#[cfg(unix)]
+pub(crate) mod listen_fds;
+mod memory;
+mod multi;
+mod proxy_protocol;
+#[cfg(feature = "quic")]
+mod quic;
+mod recording;
+mod socket_addrs;
+mod stdio;
 mod tcp;
+mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
+mod udp;
+#[cfg(unix)]
+mod unix;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 
-use crate::TransportError;
+use crate::{Extensions, TransportError};
 
-pub use tcp::TcpTransport;
+pub use admission::{AdmissionTransport, AdmittedConnection};
+pub use chaos::{ChaosConnection, ChaosTransport};
+pub use cidr::Cidr;
+#[cfg(unix)]
+pub use listen_fds::ListenFd;
+pub use memory::{MemoryClient, MemoryTransport};
+pub use multi::{
+    EitherAddr, EitherConnection, EitherTransport, MultiAddr, MultiConnection, MultiTransport,
+};
+pub use proxy_protocol::{ProxyConnection, ProxyHeader, ProxyProtocolTransport};
+#[cfg(feature = "quic")]
+pub use quic::{QuicStream, QuicTransport};
+pub use recording::{
+    Capture, Direction, RecordedConnection, RecordedEvent, RecordingConnection, RecordingTransport,
+    ReplayMismatch, ReplayReport,
+};
+pub use socket_addrs::SocketAddrs;
+pub use stdio::{StdioConnection, StdioTransport};
+pub use tcp::{TcpKeepalive, TcpTransport};
+pub use throttle::{ByteCount, ThrottledConnection, ThrottledTransport};
+#[cfg(feature = "tls")]
+pub use tls::{
+    PeerCertificate, SelfSignedCert, SubjectAltName, TlsConfig, TlsConnection, TlsInfo,
+    TlsTransport,
+};
+pub use udp::{UdpDatagram, UdpTransport};
+#[cfg(unix)]
+pub use unix::{PeerCred, UnixAddr, UnixTransport};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use uring::{UringConnection, UringTransport};
 
 pub type TransportResult<T> = std::result::Result<T, TransportError>;
 
+/// Locks `mutex`, even if a thread panicked while holding it.
+///
+/// Only for state that a panic can't leave invalid, e.g. because it is updated in a single step.
+/// A poisoned lock would otherwise make every later connection fail.
+pub(crate) fn lock_ignoring_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Implements the listed [Transport] methods of a wrapper transport by delegating them to its
+/// `inner` transport, whose connections are the `inner` field of the wrapper's connections.
+macro_rules! delegate_to_inner {
+    ($($method:ident),+ $(,)?) => {
+        $($crate::transport::delegate_to_inner!(@ $method);)+
+    };
+    (@ bind) => {
+        async fn bind(&mut self, local_addr: Self::Addr) -> $crate::transport::TransportResult<()> {
+            self.inner.bind(local_addr).await
+        }
+    };
+    (@ peer_addr) => {
+        fn peer_addr(&self, conn: &Self::Connection) -> Option<std::net::SocketAddr> {
+            self.inner.peer_addr(&conn.inner)
+        }
+    };
+    (@ conn_local_addr) => {
+        fn conn_local_addr(&self, conn: &Self::Connection) -> Option<std::net::SocketAddr> {
+            self.inner.conn_local_addr(&conn.inner)
+        }
+    };
+    (@ kind) => {
+        fn kind(&self, conn: &Self::Connection) -> &'static str {
+            self.inner.kind(&conn.inner)
+        }
+    };
+    (@ extensions) => {
+        fn extensions(&self, conn: &Self::Connection) -> $crate::Extensions {
+            self.inner.extensions(&conn.inner)
+        }
+    };
+    (@ listeners) => {
+        #[cfg(unix)]
+        fn listeners(&self) -> Vec<std::os::fd::BorrowedFd<'_>> {
+            self.inner.listeners()
+        }
+    };
+    (@ set_reuse_port) => {
+        #[cfg(unix)]
+        fn set_reuse_port(&mut self, reuse_port: bool) -> $crate::transport::TransportResult<()> {
+            self.inner.set_reuse_port(reuse_port)
+        }
+    };
+    (@ shutdown) => {
+        async fn shutdown(&self) -> $crate::transport::TransportResult<()> {
+            self.inner.shutdown().await
+        }
+    };
+}
+pub(crate) use delegate_to_inner;
+
 /// Generic transport layer
 ///
-/// Only transport that use socket addresses (e.g. TCP, UDP) can be supported, because of
-/// [Transport::bind] accepting an argument that implements [ToSocketAddrs].
+/// [`YarsServer::listen`][crate::YarsServer::listen] binds the transport to the given address
+/// once, then accepts connections in a loop. Each connection is read from, written to and shut
+/// down in its own task.
+///
+/// See `examples/custom_transport.rs` for an example of a custom transport.
 pub trait Transport: Send + Sync + 'static {
+    /// The address the transport binds to.
+    ///
+    /// This is whatever the transport knows how to listen on, e.g. [SocketAddrs] for TCP and UDP,
+    /// [UnixAddr] for Unix domain sockets, a file path, or a file descriptor inherited from a
+    /// parent process (see [ListenFd]). Transports that don't need an address (e.g. in-memory or stdio) should
+    /// use `()`.
+    ///
+    /// [`YarsServer::listen`][crate::YarsServer::listen] accepts anything that converts into it.
+    type Addr: Send;
+
     /// TODO
     ///
-    /// Connection-less transports should use `()`.
+    /// Connection-less transports (e.g. UDP) should treat each datagram as its own connection,
+    /// which also carries the peer address so [Transport::write] knows where to send the response.
     type Connection: Send + Sync;
 
     /// Bind the transport to its listening address.
@@ -32,16 +177,34 @@ pub trait Transport: Send + Sync + 'static {
     /// Should provide a detailed log message saying that the transport is listening on the given address.
     fn bind(
         &mut self,
-        local_addr: impl ToSocketAddrs,
+        local_addr: Self::Addr,
     ) -> impl std::future::Future<Output = TransportResult<()>>;
 
     /// TODO
     /// Accept a new connection.
     ///
-    /// Connection-less transports should return `Ok(())`.
+    /// Connection-less transports should wait for the next datagram.
+    ///
+    /// Transports that will never accept another connection (e.g. stdio, which only has a single
+    /// connection) should return [`TransportError::Closed`]. The server then waits for open
+    /// connections to finish and stops listening.
+    ///
+    /// Must be cancel safe: dropping the future before it completes mustn't lose a connection.
+    /// [MultiTransport] drops the accepts of the transports that lose the race, and the server
+    /// drops an accept in progress when it shuts down or hands its listeners over on a hot
+    /// restart. Awaiting nothing after the underlying accept is enough.
     fn accept(&self) -> impl std::future::Future<Output = TransportResult<Self::Connection>>;
 
-    /// TODO
+    /// Read the next bytes from `conn`. The server keeps reading until the protocol has a whole
+    /// request, see [`Protocol::request_complete`][crate::protocol::Protocol::request_complete].
+    ///
+    /// An empty read means the end of the stream, e.g. the peer closed the connection. Transports
+    /// must return an empty `Vec` once everything has been read, rather than the same bytes again.
+    ///
+    /// Must be cancel safe: dropping the future before it completes mustn't lose bytes, so the
+    /// next read returns them. [Socks5Protocol][crate::protocol::Socks5Protocol] drops a read
+    /// from the client whenever the upstream sends something first. Anything a read has made
+    /// progress on (e.g. a TLS handshake, or bytes held back by throttling) belongs in `conn`.
     fn read(
         &self,
         conn: &mut Self::Connection,
@@ -60,5 +223,64 @@ pub trait Transport: Send + Sync + 'static {
         conn: Self::Connection,
     ) -> impl Future<Output = TransportResult<()>> + Send;
 
-    // TODO?: shutdown entire transport
+    /// The address of the peer on the other end of `conn`, which is recorded on the connection's
+    /// tracing span.
+    ///
+    /// Defaults to `None`, for transports that aren't addressed by a [SocketAddr].
+    fn peer_addr(&self, _conn: &Self::Connection) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The local address that `conn` was accepted on, which is reported to handlers in
+    /// [ConnectionInfo][crate::ConnectionInfo].
+    ///
+    /// Defaults to `None`, for transports that aren't addressed by a [SocketAddr].
+    fn conn_local_addr(&self, _conn: &Self::Connection) -> Option<SocketAddr> {
+        None
+    }
+
+    /// A short name for the kind of transport that accepted `conn`, e.g. `"tcp"`, which is
+    /// reported to handlers in [ConnectionInfo][crate::ConnectionInfo]. Wrappers that only change
+    /// how a connection behaves (e.g. [ChaosTransport]) should report the kind of the transport
+    /// they wrap.
+    ///
+    /// Defaults to the type name of the transport.
+    fn kind(&self, _conn: &Self::Connection) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Connection-level information that is attached to every request read from `conn`, e.g.
+    /// the credentials of the peer.
+    ///
+    /// Defaults to no extensions.
+    fn extensions(&self, _conn: &Self::Connection) -> Extensions {
+        Extensions::new()
+    }
+
+    /// The listening sockets of the transport, which are handed over to a new process on a hot
+    /// restart, see [`YarsServer::hot_restart_on`][crate::YarsServer::hot_restart_on].
+    ///
+    /// Defaults to none, in which case the transport can't be hot restarted.
+    #[cfg(unix)]
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        Vec::new()
+    }
+
+    /// Bind with `SO_REUSEPORT`, so that multiple processes can listen on the same address, see
+    /// [`YarsServer::workers`][crate::YarsServer::workers]. Called before [Transport::bind].
+    ///
+    /// Defaults to an error, for transports that don't support it.
+    #[cfg(unix)]
+    fn set_reuse_port(&mut self, _reuse_port: bool) -> TransportResult<()> {
+        Err(TransportError::Generic(
+            "Transport doesn't support SO_REUSEPORT".into(),
+        ))
+    }
+
+    /// Shut down the entire transport, once the server has stopped accepting connections.
+    ///
+    /// Defaults to doing nothing.
+    fn shutdown(&self) -> impl Future<Output = TransportResult<()>> + Send {
+        async { Ok(()) }
+    }
 }
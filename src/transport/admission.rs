@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{info, warn};
+
+use super::{delegate_to_inner, lock_ignoring_poison, Cidr, Transport, TransportResult};
+
+/// Wraps any transport, only admitting connections from peers that pass its rules. Rules are
+/// checked when a connection is accepted, and rejected connections are closed before anything is
+/// read from them.
+///
+/// - Peers in a [`deny`][Self::deny] block are rejected
+/// - If any [`allow`][Self::allow] blocks are set, peers outside them are rejected
+/// - Peers with [`max_connections_per_ip`][Self::max_connections_per_ip] open connections are
+///   rejected
+///
+/// The peer is the address known to the inner transport when accepting. Connections whose peer
+/// isn't known, e.g. in-memory connections, are rejected if there are allow blocks, and
+/// otherwise admitted. Behind a proxy, every connection has the proxy's address, so wrap this
+/// transport in a [`ProxyProtocolTransport`][super::ProxyProtocolTransport] to restrict which
+/// proxies can connect rather than which clients. Wrap it in a
+/// `TlsTransport` so connections are rejected before the handshake.
+///
+/// Every rejection is logged with the total number of connections rejected, which is also
+/// available from [`rejected`][Self::rejected].
+///
+/// ```rust
+/// # fn main() -> yars::transport::TransportResult<()> {
+/// use yars::transport::{AdmissionTransport, TcpTransport};
+///
+/// // Only reachable from internal ranges
+/// let transport = AdmissionTransport::new(TcpTransport::new())
+///     .allow(["10.0.0.0/8".parse()?, "fd00::/8".parse()?])
+///     .deny(["10.66.0.0/16".parse()?])
+///     .max_connections_per_ip(16);
+/// # Ok(())
+/// # }
+/// ```
+pub struct AdmissionTransport<T> {
+    inner: T,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    max_per_ip: Option<usize>,
+    /// Number of open connections from each IP address, only tracked if `max_per_ip` is set
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    rejected: AtomicU64,
+}
+
+/// Connection admitted by [`AdmissionTransport`]
+pub struct AdmittedConnection<C> {
+    inner: C,
+    /// Frees the connection's place in its peer's limit when dropped
+    _slot: Option<PeerSlot>,
+}
+
+/// A connection counted towards its peer's limit
+struct PeerSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        let mut open = lock_ignoring_poison(&self.open);
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl<C> AdmittedConnection<C> {
+    /// The wrapped connection
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+/// So that stream-based transports like `TlsTransport` can wrap an
+/// [`AdmissionTransport`], rejecting connections before any handshake
+impl<C: AsyncRead + Unpin> AsyncRead for AdmittedConnection<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for AdmittedConnection<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Transport> AdmissionTransport<T> {
+    /// Admits every connection until rules are added
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_per_ip: None,
+            open: Arc::new(Mutex::new(HashMap::new())),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Only admit peers in these blocks
+    pub fn allow(mut self, blocks: impl IntoIterator<Item = Cidr>) -> Self {
+        self.allow.extend(blocks);
+        self
+    }
+
+    /// Reject peers in these blocks, even if they're also allowed
+    pub fn deny(mut self, blocks: impl IntoIterator<Item = Cidr>) -> Self {
+        self.deny.extend(blocks);
+        self
+    }
+
+    /// Reject connections from IP addresses that already have `max` connections open
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_per_ip = Some(max);
+        self
+    }
+
+    /// Number of connections rejected so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Relaxed)
+    }
+
+    /// The wrapped transport
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Checks the rules for `peer`, returning why it's rejected, or its place in its IP address's
+    /// limit if it's admitted
+    fn admit(&self, peer: Option<SocketAddr>) -> Result<Option<PeerSlot>, &'static str> {
+        let Some(ip) = peer.map(|peer| peer.ip()) else {
+            return match self.allow.is_empty() {
+                true => Ok(None),
+                false => Err("unknown peer"),
+            };
+        };
+
+        if self.deny.iter().any(|block| block.contains(ip)) {
+            return Err("denied");
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|block| block.contains(ip)) {
+            return Err("not allowed");
+        }
+
+        let Some(max) = self.max_per_ip else {
+            return Ok(None);
+        };
+        let ip = ip.to_canonical();
+        let mut open = lock_ignoring_poison(&self.open);
+        let count = open.entry(ip).or_default();
+        if *count >= max {
+            return Err("too many connections");
+        }
+        *count += 1;
+        Ok(Some(PeerSlot {
+            ip,
+            open: self.open.clone(),
+        }))
+    }
+}
+
+impl<T: Transport> Transport for AdmissionTransport<T> {
+    type Addr = T::Addr;
+
+    type Connection = AdmittedConnection<T::Connection>;
+
+    async fn bind(&mut self, local_addr: Self::Addr) -> TransportResult<()> {
+        self.inner.bind(local_addr).await?;
+        info!(
+            allow = ?self.allow,
+            deny = ?self.deny,
+            max_per_ip = self.max_per_ip,
+            "Admission rules enabled"
+        );
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        loop {
+            let conn = self.inner.accept().await?;
+
+            let peer = self.inner.peer_addr(&conn);
+            match self.admit(peer) {
+                Ok(slot) => {
+                    return Ok(AdmittedConnection {
+                        inner: conn,
+                        _slot: slot,
+                    })
+                }
+                Err(reason) => {
+                    let rejected = self.rejected.fetch_add(1, Relaxed) + 1;
+                    // Dropping the connection closes it
+                    warn!(?peer, reason, rejected, "Rejected connection");
+                }
+            }
+        }
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        self.inner.read(&mut conn.inner).await
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        self.inner.write(&mut conn.inner, response).await
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        self.inner.shutdown_conn(conn.inner).await
+    }
+
+    delegate_to_inner!(
+        peer_addr,
+        conn_local_addr,
+        kind,
+        extensions,
+        listeners,
+        set_reuse_port,
+        shutdown
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
+
+    use super::*;
+    use crate::transport::{MemoryTransport, TcpTransport};
+
+    async fn bound(
+        transport: AdmissionTransport<TcpTransport>,
+    ) -> AdmissionTransport<TcpTransport> {
+        let mut transport = transport;
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        transport
+    }
+
+    /// Whether a connection from the client is accepted. Rejected connections are closed.
+    async fn is_admitted(
+        transport: &AdmissionTransport<TcpTransport>,
+    ) -> Option<AdmittedConnection<tokio::net::TcpStream>> {
+        let mut client = TcpStream::connect(transport.inner().local_addr().unwrap())
+            .await
+            .unwrap();
+        match timeout(Duration::from_millis(200), transport.accept()).await {
+            Ok(conn) => Some(conn.unwrap()),
+            Err(_) => {
+                let read = client.read(&mut [0; 1]).await;
+                assert!(matches!(read, Ok(0)) || read.is_err());
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn allows_and_denies_blocks() {
+        let transport = bound(
+            AdmissionTransport::new(TcpTransport::new()).allow(["10.0.0.0/8".parse().unwrap()]),
+        )
+        .await;
+        assert!(is_admitted(&transport).await.is_none());
+        assert_eq!(transport.rejected(), 1);
+
+        let transport = bound(
+            AdmissionTransport::new(TcpTransport::new())
+                .allow(["127.0.0.0/8".parse().unwrap()])
+                .deny(["127.0.0.1".parse().unwrap()]),
+        )
+        .await;
+        assert!(is_admitted(&transport).await.is_none());
+
+        let transport = bound(
+            AdmissionTransport::new(TcpTransport::new())
+                .allow(["::1".parse().unwrap(), "127.0.0.0/8".parse().unwrap()]),
+        )
+        .await;
+        assert!(is_admitted(&transport).await.is_some());
+        assert_eq!(transport.rejected(), 0);
+    }
+
+    #[tokio::test]
+    async fn limits_connections_per_ip() {
+        let transport =
+            bound(AdmissionTransport::new(TcpTransport::new()).max_connections_per_ip(2)).await;
+
+        let first = is_admitted(&transport).await.unwrap();
+        let second = is_admitted(&transport).await.unwrap();
+        assert!(is_admitted(&transport).await.is_none());
+
+        transport.shutdown_conn(first).await.unwrap();
+        let third = is_admitted(&transport).await.unwrap();
+        // Dropping a connection also frees its place
+        drop(second);
+        let _fourth = is_admitted(&transport).await.unwrap();
+        drop(third);
+        assert_eq!(transport.rejected(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_peers_only_with_allow_blocks() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = AdmissionTransport::new(inner).max_connections_per_ip(1);
+        transport.bind(()).await.unwrap();
+        let _first = client.connect().unwrap();
+        let _second = client.connect().unwrap();
+        transport.accept().await.unwrap();
+        transport.accept().await.unwrap();
+
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = AdmissionTransport::new(inner).allow(["0.0.0.0/0".parse().unwrap()]);
+        transport.bind(()).await.unwrap();
+        let _stream = client.connect().unwrap();
+        drop(client);
+        assert!(transport.accept().await.is_err());
+        assert_eq!(transport.rejected(), 1);
+    }
+}
@@ -0,0 +1,357 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::sleep;
+use tracing::info;
+
+use super::{delegate_to_inner, lock_ignoring_poison, Transport, TransportResult};
+
+/// Wraps any transport, injecting faults to check that protocols and handlers cope with
+/// real-world network behaviour.
+///
+/// Every fault is off by default, and is enabled with a probability from 0 to 1 that it happens
+/// on each read or write. Decisions are made with an RNG seeded with the given seed, so a run
+/// with a single connection is reproducible. With concurrent connections the order in which they
+/// use the RNG isn't deterministic.
+///
+/// Injected faults are logged at info level.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use yars::transport::{ChaosTransport, TcpTransport};
+///
+/// let transport = ChaosTransport::new(TcpTransport::new(), 42)
+///     .fragment_reads(0.5, 4)
+///     .latency(0.1, Duration::from_millis(200))
+///     .reset_writes(0.01);
+/// ```
+pub struct ChaosTransport<T> {
+    inner: T,
+    rng: Mutex<StdRng>,
+    fragment: Option<(f64, usize)>,
+    latency: Option<(f64, Duration)>,
+    reset: f64,
+    truncate: f64,
+    stall: f64,
+}
+
+/// Connection accepted by [`ChaosTransport`]
+pub struct ChaosConnection<C> {
+    inner: C,
+    /// Bytes that have been read from the inner connection, but held back by a fragmented read
+    pending: Vec<u8>,
+    /// Set once a reset has been injected, after which the connection can't be used
+    reset: bool,
+}
+
+impl<C> ChaosConnection<C> {
+    /// The wrapped connection
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+fn reset_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionReset,
+        "connection reset by chaos transport",
+    )
+}
+
+impl<T: Transport> ChaosTransport<T> {
+    pub fn new(inner: T, seed: u64) -> Self {
+        Self {
+            inner,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            fragment: None,
+            latency: None,
+            reset: 0.0,
+            truncate: 0.0,
+            stall: 0.0,
+        }
+    }
+
+    /// Return only the first 1 to `max_len` bytes of a read, holding the rest back for the
+    /// following reads, like a request split across TCP segments
+    pub fn fragment_reads(mut self, probability: f64, max_len: usize) -> Self {
+        self.fragment = Some((probability, max_len.max(1)));
+        self
+    }
+
+    /// Wait for a random time up to `max` before a read or write
+    pub fn latency(mut self, probability: f64, max: Duration) -> Self {
+        self.latency = Some((probability, max));
+        self
+    }
+
+    /// Reset the connection partway through a write: some of the response is written, then the
+    /// write fails and the connection is closed without being shut down
+    pub fn reset_writes(mut self, probability: f64) -> Self {
+        self.reset = probability;
+        self
+    }
+
+    /// Only write the start of a response, while reporting success
+    pub fn truncate_writes(mut self, probability: f64) -> Self {
+        self.truncate = probability;
+        self
+    }
+
+    /// Never complete a read or write, like a peer that has gone away without closing the
+    /// connection
+    pub fn stall(mut self, probability: f64) -> Self {
+        self.stall = probability;
+        self
+    }
+
+    /// The wrapped transport
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn chance(&self, probability: f64) -> bool {
+        probability > 0.0 && self.rng().random_bool(probability.min(1.0))
+    }
+
+    fn rng(&self) -> std::sync::MutexGuard<'_, StdRng> {
+        lock_ignoring_poison(&self.rng)
+    }
+
+    /// Injects the faults that can happen before any read or write
+    async fn before_io(&self, op: &str) {
+        if self.chance(self.stall) {
+            info!(fault = "stall", op, "Injected fault");
+            std::future::pending::<()>().await;
+        }
+
+        if let Some((probability, max)) = self.latency {
+            if self.chance(probability) {
+                let delay = self.rng().random_range(Duration::ZERO..=max);
+                info!(fault = "latency", op, ?delay, "Injected fault");
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
+impl<T: Transport> Transport for ChaosTransport<T> {
+    type Addr = T::Addr;
+
+    type Connection = ChaosConnection<T::Connection>;
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let inner = self.inner.accept().await?;
+        Ok(ChaosConnection {
+            inner,
+            pending: Vec::new(),
+            reset: false,
+        })
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        self.before_io("read").await;
+        if conn.reset {
+            return Err(reset_error().into());
+        }
+
+        let mut buf = if conn.pending.is_empty() {
+            self.inner.read(&mut conn.inner).await?
+        } else {
+            std::mem::take(&mut conn.pending)
+        };
+
+        if let Some((probability, max_len)) = self.fragment {
+            if buf.len() > 1 && self.chance(probability) {
+                let len = self.rng().random_range(1..=max_len.min(buf.len() - 1));
+                info!(
+                    fault = "fragment",
+                    len,
+                    held_back = buf.len() - len,
+                    "Injected fault"
+                );
+                conn.pending = buf.split_off(len);
+            }
+        }
+
+        Ok(buf)
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        self.before_io("write").await;
+        if conn.reset {
+            return Err(reset_error().into());
+        }
+
+        if self.chance(self.reset) {
+            let len = self.rng().random_range(0..=response.len());
+            info!(fault = "reset", len, "Injected fault");
+            self.inner.write(&mut conn.inner, &response[..len]).await?;
+            conn.reset = true;
+            return Err(reset_error().into());
+        }
+
+        if !response.is_empty() && self.chance(self.truncate) {
+            let len = self.rng().random_range(0..response.len());
+            info!(fault = "truncate", len, "Injected fault");
+            return self.inner.write(&mut conn.inner, &response[..len]).await;
+        }
+
+        self.inner.write(&mut conn.inner, response).await
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        if conn.reset {
+            // Dropping the connection closes it without shutting it down gracefully
+            return Ok(());
+        }
+        self.inner.shutdown_conn(conn.inner).await
+    }
+
+    delegate_to_inner!(
+        bind,
+        peer_addr,
+        conn_local_addr,
+        kind,
+        extensions,
+        listeners,
+        set_reuse_port,
+        shutdown
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+        transport::MemoryTransport,
+        YarsServer,
+    };
+
+    async fn bind(transport: &mut ChaosTransport<MemoryTransport>) {
+        transport.bind(()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fragments_reads_without_losing_bytes() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = ChaosTransport::new(inner, 1).fragment_reads(1.0, 3);
+        bind(&mut transport).await;
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+        client_stream
+            .write_all(b"GET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut request = Vec::new();
+        while request.len() < 18 {
+            let fragment = transport.read(&mut conn).await.unwrap();
+            assert!((1..=3).contains(&fragment.len()));
+            request.extend(fragment);
+        }
+        assert_eq!(request, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn same_seed_injects_same_faults() {
+        async fn fragment_lens(seed: u64) -> Vec<usize> {
+            let (inner, client) = MemoryTransport::new();
+            let mut transport = ChaosTransport::new(inner, seed).fragment_reads(0.5, 8);
+            bind(&mut transport).await;
+
+            let mut client_stream = client.connect().unwrap();
+            let mut conn = transport.accept().await.unwrap();
+            client_stream.write_all(&[0; 64]).await.unwrap();
+
+            let mut lens = Vec::new();
+            while lens.iter().sum::<usize>() < 64 {
+                lens.push(transport.read(&mut conn).await.unwrap().len());
+            }
+            lens
+        }
+
+        assert_eq!(fragment_lens(7).await, fragment_lens(7).await);
+    }
+
+    #[tokio::test]
+    async fn reset_closes_connection_mid_write() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = ChaosTransport::new(inner, 1).reset_writes(1.0);
+        bind(&mut transport).await;
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+
+        let err = transport.write(&mut conn, b"response").await.unwrap_err();
+        assert!(err.to_string().contains("reset"));
+        assert!(transport.read(&mut conn).await.is_err());
+        transport.shutdown_conn(conn).await.unwrap();
+
+        let mut response = Vec::new();
+        client_stream.read_to_end(&mut response).await.unwrap();
+        assert!(b"response".starts_with(&response));
+    }
+
+    #[tokio::test]
+    async fn truncates_writes() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = ChaosTransport::new(inner, 1).truncate_writes(1.0);
+        bind(&mut transport).await;
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+        transport.write(&mut conn, b"response").await.unwrap();
+        transport.shutdown_conn(conn).await.unwrap();
+
+        let mut response = Vec::new();
+        client_stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.len() < 8);
+        assert!(b"response".starts_with(&response));
+    }
+
+    #[tokio::test]
+    async fn stalls() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = ChaosTransport::new(inner, 1).stall(1.0);
+        bind(&mut transport).await;
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+        client_stream.write_all(b"request").await.unwrap();
+
+        let read = timeout(Duration::from_millis(50), transport.read(&mut conn)).await;
+        assert!(read.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_parses_fragmented_requests() {
+        let (inner, client) = MemoryTransport::new();
+        let transport = ChaosTransport::new(inner, 1).fragment_reads(1.0, 3);
+        let server = YarsServer::new(transport, HttpProtocol)
+            .get("/", async |_req: HttpRequest| -> crate::Result<_> {
+                Ok(HttpResponse::Ok().text("Hello"))
+            });
+        let server = tokio::spawn(server.listen(()));
+
+        let response = client
+            .send(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(
+            response.ends_with(b"Hello"),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::TransportError;
+
+/// A block of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`
+///
+/// A single address without a prefix length, e.g. `127.0.0.1`, is a block containing only that
+/// address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Fails if `prefix_len` is longer than the address
+    ///
+    /// IPv4-mapped IPv6 blocks, e.g. `::ffff:10.0.0.0/104`, become the IPv4 block they map to, e.g.
+    /// `10.0.0.0/8`.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, TransportError> {
+        let max_len = max_prefix_len(addr);
+        if prefix_len > max_len {
+            return Err(TransportError::Generic(format!(
+                "Invalid CIDR prefix length {prefix_len} for {addr}, must be at most {max_len}"
+            )));
+        }
+        Ok(Self::canonical(addr, prefix_len))
+    }
+
+    fn canonical(addr: IpAddr, prefix_len: u8) -> Self {
+        match addr {
+            IpAddr::V6(v6) if prefix_len >= MAPPED_PREFIX_LEN => match v6.to_ipv4_mapped() {
+                Some(v4) => Self {
+                    addr: IpAddr::V4(v4),
+                    prefix_len: prefix_len - MAPPED_PREFIX_LEN,
+                },
+                None => Self { addr, prefix_len },
+            },
+            _ => Self { addr, prefix_len },
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `ip` is in this block. IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are treated as
+    /// the IPv4 address they map to, and IPv4 addresses are in IPv6 blocks that contain their
+    /// mapped address (e.g. `::/0`).
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                net.to_bits().into(),
+                ip.to_bits().into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(net.to_bits(), ip.to_bits(), 128, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V4(ip)) => prefix_matches(
+                net.to_bits(),
+                ip.to_ipv6_mapped().to_bits(),
+                128,
+                self.prefix_len,
+            ),
+            (IpAddr::V4(_), IpAddr::V6(_)) => false,
+        }
+    }
+}
+
+/// Length of the `::ffff:0:0/96` prefix of IPv4-mapped IPv6 addresses
+const MAPPED_PREFIX_LEN: u8 = 96;
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    // Shifting by the full width would overflow, and a zero length prefix matches everything
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    net >> shift == ip >> shift
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        Self::canonical(addr, max_prefix_len(addr))
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = TransportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TransportError::Generic(format!("Invalid CIDR: {s}"));
+
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Self::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix_len.parse().map_err(|_| invalid())?,
+            ),
+            None => Ok(Self::from(s.parse::<IpAddr>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn contains_addresses_in_block() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.0.1")));
+        assert!(cidr.contains(ip("10.1.255.255")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(ip("2001:db8::1")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn single_address_is_full_length_block() {
+        let cidr: Cidr = "127.0.0.1".parse().unwrap();
+        assert_eq!(cidr.prefix_len(), 32);
+        assert!(cidr.contains(ip("127.0.0.1")));
+        assert!(!cidr.contains(ip("127.0.0.2")));
+    }
+
+    #[test]
+    fn treats_mapped_blocks_as_ipv4() {
+        let cidr: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(cidr, "10.0.0.0/8".parse().unwrap());
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+
+        let cidr: Cidr = "::ffff:127.0.0.1".parse().unwrap();
+        assert_eq!(cidr, "127.0.0.1".parse().unwrap());
+
+        let cidr: Cidr = "::/0".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("::1")));
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use std::env;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use socket2::{SockAddr, SockRef, Type};
+use tracing::{debug, warn};
+
+use super::TransportResult;
+use crate::TransportError;
+
+/// The first file descriptor passed by systemd, see `sd_listen_fds(3)`
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Comma separated file descriptors of the sockets handed over by the previous process on a hot
+/// restart, see [`YarsServer::hot_restart_on`][crate::YarsServer::hot_restart_on]
+pub(crate) const HANDOFF_FDS_ENV: &str = "YARS_LISTEN_FDS";
+/// PID of the process that handed over its sockets, which must be our parent
+pub(crate) const HANDOFF_PARENT_ENV: &str = "YARS_LISTEN_PARENT";
+/// File descriptor to write to once the handed over sockets are being listened on
+pub(crate) const HANDOFF_READY_ENV: &str = "YARS_LISTEN_READY_FD";
+
+/// Selects one of the listening sockets passed to the process by systemd socket activation
+/// (`LISTEN_FDS`/`LISTEN_PID`/`LISTEN_FDNAMES`), to be adopted by a transport instead of binding
+/// a new socket.
+///
+/// Can be used as the address of [`TcpTransport`][super::TcpTransport],
+/// [`UdpTransport`][super::UdpTransport] and [`UnixTransport`][super::UnixTransport]. Each socket
+/// can only be adopted once.
+///
+/// Those transports also adopt a passed socket when binding to an ordinary address that it is
+/// already bound to, so the same address works with or without socket activation.
+///
+/// ```rust,no_run
+/// # async fn run() -> yars::Result<()> {
+/// use yars::{protocol::HttpProtocol, transport::{ListenFd, TcpTransport}, YarsServer};
+///
+/// // With `FileDescriptorName=http` in the socket unit
+/// YarsServer::new(TcpTransport::new(), HttpProtocol)
+///     .listen(ListenFd::name("http"))
+///     .await
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenFd {
+    /// The socket at this position, starting from 0, in the order of the socket unit
+    Index(usize),
+    /// The socket named by `FileDescriptorName=` in the socket unit, which defaults to the name
+    /// of the unit. If several sockets have the same name, each is adopted in turn.
+    Name(String),
+}
+
+/// A socket passed to this process, which is taken when it is adopted
+struct PassedFd {
+    name: Option<String>,
+    fd: Option<RawFd>,
+}
+
+/// Sockets passed to this process by systemd or by the previous process on a hot restart
+#[derive(Default)]
+struct Inherited {
+    fds: Vec<PassedFd>,
+    /// Where to tell the previous process that we are ready, see [notify_ready]
+    ready_fd: Option<RawFd>,
+}
+
+impl ListenFd {
+    pub fn index(index: usize) -> Self {
+        Self::Index(index)
+    }
+
+    pub fn name(name: impl Into<String>) -> Self {
+        Self::Name(name.into())
+    }
+
+    /// Takes ownership of the selected socket
+    pub(crate) fn take(&self) -> TransportResult<OwnedFd> {
+        let mut inherited = inherited();
+        let fds = &mut inherited.fds;
+
+        let passed_fd = match self {
+            Self::Index(index) => fds.get_mut(*index),
+            // Prefer a socket that hasn't been adopted yet
+            Self::Name(name) => {
+                let named = |passed_fd: &PassedFd| passed_fd.name.as_ref() == Some(name);
+                fds.iter()
+                    .position(|passed_fd| named(passed_fd) && passed_fd.fd.is_some())
+                    .or_else(|| fds.iter().position(named))
+                    .map(|index| &mut fds[index])
+            }
+        }
+        .ok_or_else(|| systemd_error(format!("No socket {self} was passed by systemd")))?;
+
+        let fd = passed_fd
+            .fd
+            .take()
+            .ok_or_else(|| systemd_error(format!("Socket {self} has already been adopted")))?;
+        debug!(fd, "Adopting socket passed by systemd");
+
+        Ok(own(fd)?)
+    }
+}
+
+impl std::fmt::Display for ListenFd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "#{index}"),
+            Self::Name(name) => write!(f, "'{name}'"),
+        }
+    }
+}
+
+fn systemd_error(message: String) -> TransportError {
+    TransportError::Generic(format!("systemd socket activation: {message}"))
+}
+
+/// Takes ownership of an inherited file descriptor
+fn own(fd: RawFd) -> std::io::Result<OwnedFd> {
+    // SAFETY: the file descriptor was passed to us, and it is only ever taken once
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // Duplicate it so that it is close-on-exec, and isn't leaked to child processes
+    fd.try_clone()
+}
+
+/// Takes an inherited socket of type `ty` that is bound to an address matching `matches`
+fn take_matching(ty: Type, matches: impl Fn(&SockAddr) -> bool) -> Option<OwnedFd> {
+    let mut inherited = inherited();
+    let passed_fd = inherited.fds.iter_mut().find(|passed_fd| {
+        passed_fd.fd.is_some_and(|fd| {
+            // SAFETY: the file descriptor stays open until it is taken
+            let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+            let socket = SockRef::from(&fd);
+            socket.r#type().is_ok_and(|socket_ty| socket_ty == ty)
+                && socket.local_addr().is_ok_and(|addr| matches(&addr))
+        })
+    })?;
+
+    let fd = passed_fd.fd.take()?;
+    debug!(fd, "Adopting inherited socket");
+    own(fd)
+        .inspect_err(|err| warn!(%err, "Failed to adopt inherited socket"))
+        .ok()
+}
+
+/// Takes an inherited socket of type `ty` that is bound to one of `addrs`
+pub(crate) fn take_socket_bound_to(ty: Type, addrs: &[SocketAddr]) -> Option<OwnedFd> {
+    take_matching(ty, |addr| {
+        addr.as_socket().is_some_and(|addr| addrs.contains(&addr))
+    })
+}
+
+/// Takes an inherited Unix socket listening on `path`
+pub(crate) fn take_unix_bound_to(path: &Path) -> Option<OwnedFd> {
+    take_matching(Type::STREAM, |addr| addr.as_pathname() == Some(path))
+}
+
+/// Takes an inherited Unix socket listening on `name` in the Linux abstract namespace
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn take_unix_abstract(name: &[u8]) -> Option<OwnedFd> {
+    take_matching(Type::STREAM, |addr| {
+        addr.as_abstract_namespace() == Some(name)
+    })
+}
+
+/// Tells the previous process that handed over its sockets that we are listening on them, so it
+/// can stop accepting connections. Does nothing if this process wasn't started by a hot restart.
+pub(crate) fn notify_ready() {
+    let Some(fd) = inherited().ready_fd.take() else {
+        return;
+    };
+
+    let result = own(fd).and_then(|fd| UnixStream::from(fd).write_all(b"1"));
+    match result {
+        Ok(()) => debug!("Notified previous process that we are ready"),
+        Err(err) => warn!(%err, "Failed to notify previous process that we are ready"),
+    }
+}
+
+/// The sockets passed to this process, read from the environment once
+fn inherited() -> std::sync::MutexGuard<'static, Inherited> {
+    static INHERITED: OnceLock<Mutex<Inherited>> = OnceLock::new();
+
+    INHERITED
+        .get_or_init(|| {
+            let var = |name| env::var(name).ok();
+
+            let systemd = parse_listen_fds(
+                var("LISTEN_PID").as_deref(),
+                var("LISTEN_FDS").as_deref(),
+                var("LISTEN_FDNAMES").as_deref(),
+                std::process::id(),
+            );
+            let handoff = parse_handoff(
+                var(HANDOFF_PARENT_ENV).as_deref(),
+                var(HANDOFF_FDS_ENV).as_deref(),
+                var(HANDOFF_READY_ENV).as_deref(),
+                std::os::unix::process::parent_id(),
+            );
+
+            let mut inherited = Inherited::default();
+            match systemd {
+                Ok(fds) => inherited.fds.extend(fds),
+                Err(err) => warn!(%err, "Ignoring invalid systemd socket activation variables"),
+            }
+            match handoff {
+                Ok((fds, ready_fd)) => {
+                    inherited.fds.extend(fds);
+                    inherited.ready_fd = ready_fd;
+                }
+                Err(err) => warn!(%err, "Ignoring invalid hot restart variables"),
+            }
+            Mutex::new(inherited)
+        })
+        .lock()
+        .expect("inherited file descriptors lock poisoned")
+}
+
+/// Parses the socket activation environment variables, see `sd_listen_fds(3)`.
+///
+/// The sockets are only meant for the process with ID `LISTEN_PID`, otherwise none were passed
+/// to this process.
+fn parse_listen_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Result<Vec<PassedFd>, String> {
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return Ok(Vec::new());
+    };
+
+    let pid: u32 = pid
+        .parse()
+        .map_err(|_| format!("Invalid LISTEN_PID {pid}"))?;
+    if pid != own_pid {
+        return Ok(Vec::new());
+    }
+
+    let count: RawFd = count
+        .parse()
+        .map_err(|_| format!("Invalid LISTEN_FDS {count}"))?;
+
+    // Names are optional, and meaningless if there isn't one for every socket
+    let names: Vec<_> = names
+        .map(|names| names.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+    let mut names = if names.len() == count as usize {
+        names.into_iter()
+    } else {
+        Vec::new().into_iter()
+    };
+
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| PassedFd {
+            name: names.next(),
+            fd: Some(fd),
+        })
+        .collect())
+}
+
+/// Parses the variables set by the previous process on a hot restart.
+///
+/// The sockets are only meant for the direct child of that process, and not for any process
+/// that inherits the environment from it.
+fn parse_handoff(
+    parent: Option<&str>,
+    fds: Option<&str>,
+    ready_fd: Option<&str>,
+    own_parent: u32,
+) -> Result<(Vec<PassedFd>, Option<RawFd>), String> {
+    let (Some(parent), Some(fds)) = (parent, fds) else {
+        return Ok((Vec::new(), None));
+    };
+
+    let parent: u32 = parent
+        .parse()
+        .map_err(|_| format!("Invalid {HANDOFF_PARENT_ENV} {parent}"))?;
+    if parent != own_parent {
+        return Ok((Vec::new(), None));
+    }
+
+    let parse_fd = |fd: &str| {
+        fd.parse::<RawFd>()
+            .map_err(|_| format!("Invalid file descriptor {fd}"))
+    };
+    let fds = fds
+        .split(',')
+        .filter(|fd| !fd.is_empty())
+        .map(|fd| {
+            Ok(PassedFd {
+                name: None,
+                fd: Some(parse_fd(fd)?),
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    let ready_fd = ready_fd.map(parse_fd).transpose()?;
+
+    Ok((fds, ready_fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        test_util::{child_addr, reserve_port, spawn_child},
+        transport::{TcpTransport, Transport},
+    };
+
+    fn fds(passed: &[PassedFd]) -> Vec<(Option<&str>, Option<RawFd>)> {
+        passed
+            .iter()
+            .map(|passed_fd| (passed_fd.name.as_deref(), passed_fd.fd))
+            .collect()
+    }
+
+    #[test]
+    fn parses_environment() {
+        let passed = parse_listen_fds(Some("42"), Some("2"), Some("http:admin"), 42).unwrap();
+        assert_eq!(
+            fds(&passed),
+            [(Some("http"), Some(3)), (Some("admin"), Some(4))]
+        );
+
+        // Names don't match the sockets
+        let passed = parse_listen_fds(Some("42"), Some("2"), Some("http"), 42).unwrap();
+        assert_eq!(fds(&passed), [(None, Some(3)), (None, Some(4))]);
+    }
+
+    #[test]
+    fn ignores_sockets_for_other_processes() {
+        assert!(parse_listen_fds(Some("41"), Some("1"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("many"), None, 42).is_err());
+    }
+
+    #[test]
+    fn parses_handoff_environment() {
+        let (passed, ready_fd) = parse_handoff(Some("42"), Some("7,9"), Some("11"), 42).unwrap();
+        assert_eq!(fds(&passed), [(None, Some(7)), (None, Some(9))]);
+        assert_eq!(ready_fd, Some(11));
+
+        // Only for the direct child of the process that handed them over
+        let (passed, ready_fd) = parse_handoff(Some("41"), Some("7,9"), Some("11"), 42).unwrap();
+        assert!(passed.is_empty());
+        assert_eq!(ready_fd, None);
+
+        assert!(parse_handoff(Some("42"), Some("7,x"), None, 42).is_err());
+    }
+
+    const CHILD: &str = "transport::listen_fds::tests::child";
+
+    /// Spawns this test binary the same way systemd would, passing a bound listener as fd 3
+    #[test]
+    fn adopts_socket_passed_by_parent() {
+        let socket = reserve_port();
+        socket.listen(128).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+
+        let mut child = spawn_child(CHILD, socket)
+            .env("LISTEN_FDNAMES", "web")
+            .spawn()
+            .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let response = runtime.block_on(async {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            response
+        });
+
+        assert!(child.wait().unwrap().success());
+        assert_eq!(response, b"ping");
+    }
+
+    /// Only does anything when spawned by [adopts_socket_passed_by_parent]
+    #[tokio::test]
+    async fn child() {
+        let Some(expected_addr) = child_addr(CHILD) else {
+            return;
+        };
+
+        let mut transport = TcpTransport::new();
+        transport.bind(ListenFd::name("web").into()).await.unwrap();
+        assert_eq!(transport.local_addr().unwrap(), expected_addr);
+
+        // Echo a single request
+        let mut conn = transport.accept().await.unwrap();
+        let request = transport.read(&mut conn).await.unwrap();
+        transport.write(&mut conn, &request).await.unwrap();
+        transport.shutdown_conn(conn).await.unwrap();
+
+        assert!(ListenFd::name("web").take().is_err());
+    }
+}
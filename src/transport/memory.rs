@@ -0,0 +1,152 @@
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::{mpsc, Mutex},
+};
+use tracing::{debug, info};
+
+use super::{Transport, TransportResult};
+use crate::{constants::MAX_REQUEST_SIZE, TransportError};
+
+/// Size of the buffer in each direction of an in-memory connection
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Implementation of the transport layer for in-memory connections, so a server can be driven
+/// entirely in-process, e.g. in tests.
+///
+/// Connections are opened with the paired [`MemoryClient`]. Once every client has been dropped
+/// the transport is closed, and the server stops listening.
+///
+/// ```rust,no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use yars::{protocol::HttpProtocol, transport::MemoryTransport, YarsServer};
+///
+/// let (transport, client) = MemoryTransport::new();
+/// tokio::spawn(YarsServer::new(transport, HttpProtocol).listen(()));
+///
+/// let response = client.send(b"GET / HTTP/1.1\r\n\r\n").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MemoryTransport {
+    incoming: Mutex<mpsc::UnboundedReceiver<DuplexStream>>,
+}
+
+/// Opens connections to a [`MemoryTransport`]
+#[derive(Clone)]
+pub struct MemoryClient {
+    connector: mpsc::UnboundedSender<DuplexStream>,
+}
+
+impl MemoryTransport {
+    /// Creates a transport, and a client that opens connections to it
+    pub fn new() -> (Self, MemoryClient) {
+        let (connector, incoming) = mpsc::unbounded_channel();
+        let transport = Self {
+            incoming: Mutex::new(incoming),
+        };
+        (transport, MemoryClient { connector })
+    }
+}
+
+impl MemoryClient {
+    /// Opens a new connection, returning the client's end of it
+    pub fn connect(&self) -> TransportResult<DuplexStream> {
+        let (client, server) = duplex(BUFFER_SIZE);
+        self.connector
+            .send(server)
+            .map_err(|_| TransportError::Memory("Memory transport has been dropped".into()))?;
+        Ok(client)
+    }
+
+    /// Opens a new connection, sends `request` and reads until the server closes the connection
+    pub async fn send(&self, request: &[u8]) -> TransportResult<Vec<u8>> {
+        let mut stream = self.connect()?;
+        stream.write_all(request).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Addr = ();
+
+    type Connection = DuplexStream;
+
+    async fn bind(&mut self, _local_addr: ()) -> TransportResult<()> {
+        info!("Listening for in-memory connections");
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let stream = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            // All clients have been dropped, so there can't be any more connections
+            .ok_or(TransportError::Closed)?;
+
+        debug!("Accepted in-memory connection");
+        Ok(stream)
+    }
+
+    async fn read(&self, stream: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        let mut buf = Vec::with_capacity(MAX_REQUEST_SIZE);
+        let len = stream.read_buf(&mut buf).await?;
+
+        debug!(len, "Successfully read from in-memory connection");
+
+        Ok(buf)
+    }
+
+    async fn write(&self, stream: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        debug!(len = response.len(), "Writing to in-memory connection");
+        stream.write_all(response).await.map_err(|err| err.into())
+    }
+
+    async fn shutdown_conn(&self, mut stream: Self::Connection) -> TransportResult<()> {
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn kind(&self, _stream: &Self::Connection) -> &'static str {
+        "memory"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn client_connections_are_accepted() {
+        let (mut transport, client) = MemoryTransport::new();
+        transport.bind(()).await.unwrap();
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+
+        client_stream.write_all(b"ping").await.unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+
+        transport.write(&mut conn, b"pong").await.unwrap();
+        transport.shutdown_conn(conn).await.unwrap();
+
+        let mut response = Vec::new();
+        client_stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"pong");
+    }
+
+    #[tokio::test]
+    async fn closes_once_clients_are_dropped() {
+        let (transport, client) = MemoryTransport::new();
+        drop(client);
+        assert!(matches!(
+            transport.accept().await,
+            Err(TransportError::Closed)
+        ));
+    }
+}
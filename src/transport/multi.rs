@@ -0,0 +1,496 @@
+use std::future::{poll_fn, Future};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::BorrowedFd;
+use std::pin::pin;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::task::Poll;
+
+use tracing::debug;
+
+use super::{Transport, TransportResult};
+use crate::{Extensions, TransportError};
+
+/// Listens on multiple addresses with the same kind of transport, e.g. both IPv4 and IPv6.
+///
+/// A transport is created for each address using the given function. Connections from all of
+/// them are served by the same server, and share the same shutdown.
+///
+/// ```rust,no_run
+/// # async fn run() -> yars::Result<()> {
+/// use yars::{protocol::HttpProtocol, transport::{MultiTransport, TcpTransport}, YarsServer};
+///
+/// YarsServer::new(MultiTransport::new(TcpTransport::new), HttpProtocol)
+///     .listen(["127.0.0.1:8000", "[::1]:8000"])
+///     .await
+/// # }
+/// ```
+///
+/// To listen with different kinds of transport, see [`EitherTransport`].
+pub struct MultiTransport<T> {
+    make_transport: Box<dyn Fn() -> T + Send + Sync>,
+    transports: Vec<T>,
+    /// Transports that have been closed, see [`TransportError::Closed`]
+    closed: Vec<AtomicBool>,
+    /// Index of the transport to poll first on the next accept, so no transport is starved
+    next: AtomicUsize,
+    /// Applied to each transport when it is created, see [`Transport::set_reuse_port`]
+    #[cfg(unix)]
+    reuse_port: Option<bool>,
+}
+
+/// Addresses for [`MultiTransport`], one for each transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiAddr<A>(pub Vec<A>);
+
+/// Connection accepted by [`MultiTransport`]
+pub struct MultiConnection<C> {
+    /// Index of the transport (and address) that accepted the connection
+    pub index: usize,
+    pub inner: C,
+}
+
+/// Listens with two different transports at once, e.g. TCP and a Unix domain socket.
+///
+/// Connections from both are served by the same server, and share the same shutdown. Can be
+/// nested to listen with more than two transports.
+///
+/// ```rust,no_run
+/// # async fn run() -> yars::Result<()> {
+/// use yars::{
+///     protocol::HttpProtocol,
+///     transport::{EitherTransport, TcpTransport, UnixTransport},
+///     YarsServer,
+/// };
+///
+/// let transport = EitherTransport::new(TcpTransport::new(), UnixTransport::new());
+/// YarsServer::new(transport, HttpProtocol)
+///     .listen(("127.0.0.1:8000", "/run/yars.sock"))
+///     .await
+/// # }
+/// ```
+pub struct EitherTransport<L, R> {
+    left: L,
+    right: R,
+    left_closed: AtomicBool,
+    right_closed: AtomicBool,
+    /// Which transport to poll first on the next accept, so neither is starved
+    left_first: AtomicBool,
+}
+
+/// Addresses for [`EitherTransport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EitherAddr<L, R> {
+    pub left: L,
+    pub right: R,
+}
+
+/// Connection accepted by [`EitherTransport`]
+pub enum EitherConnection<L, R> {
+    Left(L),
+    Right(R),
+}
+
+impl<T> MultiTransport<T>
+where
+    T: Transport,
+{
+    pub fn new(make_transport: impl Fn() -> T + Send + Sync + 'static) -> Self {
+        Self {
+            make_transport: Box::new(make_transport),
+            transports: Vec::new(),
+            closed: Vec::new(),
+            next: AtomicUsize::new(0),
+            #[cfg(unix)]
+            reuse_port: None,
+        }
+    }
+
+    /// The bound transports, in the same order as their addresses
+    pub fn transports(&self) -> &[T] {
+        &self.transports
+    }
+}
+
+impl<A, I, const N: usize> From<[I; N]> for MultiAddr<A>
+where
+    I: Into<A>,
+{
+    fn from(addrs: [I; N]) -> Self {
+        Self(addrs.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<A, I> From<Vec<I>> for MultiAddr<A>
+where
+    I: Into<A>,
+{
+    fn from(addrs: Vec<I>) -> Self {
+        Self(addrs.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T> Transport for MultiTransport<T>
+where
+    T: Transport,
+{
+    type Addr = MultiAddr<T::Addr>;
+
+    type Connection = MultiConnection<T::Connection>;
+
+    async fn bind(&mut self, local_addrs: Self::Addr) -> TransportResult<()> {
+        if local_addrs.0.is_empty() {
+            return Err(TransportError::Generic(
+                "No addresses given to listen on".into(),
+            ));
+        }
+
+        for local_addr in local_addrs.0 {
+            let mut transport = (self.make_transport)();
+            #[cfg(unix)]
+            if let Some(reuse_port) = self.reuse_port {
+                transport.set_reuse_port(reuse_port)?;
+            }
+            transport.bind(local_addr).await?;
+            self.transports.push(transport);
+            self.closed.push(AtomicBool::new(false));
+        }
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let count = self.transports.len();
+        if count == 0 {
+            // Error should never happen because this should only be used internally
+            return Err(TransportError::Generic(
+                "No transports bound. Call `bind` first.".into(),
+            ));
+        }
+        let start = self.next.fetch_add(1, Relaxed) % count;
+
+        let mut accepts: Vec<_> = self
+            .transports
+            .iter()
+            .map(|transport| Some(Box::pin(transport.accept())))
+            .collect();
+        // Closed transports are never polled again
+        for (accept, closed) in accepts.iter_mut().zip(&self.closed) {
+            if closed.load(Relaxed) {
+                *accept = None;
+            }
+        }
+
+        poll_fn(|cx| {
+            for index in (start..count).chain(0..start) {
+                let Some(accept) = &mut accepts[index] else {
+                    continue;
+                };
+
+                match accept.as_mut().poll(cx) {
+                    Poll::Ready(Err(TransportError::Closed)) => {
+                        debug!(index, "Transport closed");
+                        self.closed[index].store(true, Relaxed);
+                        accepts[index] = None;
+                    }
+                    Poll::Ready(result) => {
+                        return Poll::Ready(result.map(|inner| MultiConnection { index, inner }));
+                    }
+                    Poll::Pending => {}
+                }
+            }
+
+            if accepts.iter().all(Option::is_none) {
+                Poll::Ready(Err(TransportError::Closed))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        self.transports[conn.index].read(&mut conn.inner).await
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        self.transports[conn.index]
+            .write(&mut conn.inner, response)
+            .await
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        self.transports[conn.index].shutdown_conn(conn.inner).await
+    }
+
+    fn peer_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        self.transports[conn.index].peer_addr(&conn.inner)
+    }
+
+    fn conn_local_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        self.transports[conn.index].conn_local_addr(&conn.inner)
+    }
+
+    fn kind(&self, conn: &Self::Connection) -> &'static str {
+        self.transports[conn.index].kind(&conn.inner)
+    }
+
+    fn extensions(&self, conn: &Self::Connection) -> Extensions {
+        self.transports[conn.index].extensions(&conn.inner)
+    }
+
+    #[cfg(unix)]
+    fn set_reuse_port(&mut self, reuse_port: bool) -> TransportResult<()> {
+        self.reuse_port = Some(reuse_port);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        self.transports
+            .iter()
+            .flat_map(|transport| transport.listeners())
+            .collect()
+    }
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        for transport in &self.transports {
+            transport.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+impl<L, R> EitherTransport<L, R>
+where
+    L: Transport,
+    R: Transport,
+{
+    pub fn new(left: L, right: R) -> Self {
+        Self {
+            left,
+            right,
+            left_closed: AtomicBool::new(false),
+            right_closed: AtomicBool::new(false),
+            left_first: AtomicBool::new(true),
+        }
+    }
+
+    pub fn left(&self) -> &L {
+        &self.left
+    }
+
+    pub fn right(&self) -> &R {
+        &self.right
+    }
+}
+
+impl<L, R, A, B> From<(A, B)> for EitherAddr<L, R>
+where
+    A: Into<L>,
+    B: Into<R>,
+{
+    fn from((left, right): (A, B)) -> Self {
+        Self {
+            left: left.into(),
+            right: right.into(),
+        }
+    }
+}
+
+impl<L, R> Transport for EitherTransport<L, R>
+where
+    L: Transport,
+    R: Transport,
+{
+    type Addr = EitherAddr<L::Addr, R::Addr>;
+
+    type Connection = EitherConnection<L::Connection, R::Connection>;
+
+    async fn bind(&mut self, local_addr: Self::Addr) -> TransportResult<()> {
+        self.left.bind(local_addr.left).await?;
+        self.right.bind(local_addr.right).await?;
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let left_first = self.left_first.fetch_xor(true, Relaxed);
+
+        let mut left = pin!(async {
+            if self.left_closed.load(Relaxed) {
+                return None;
+            }
+            match self.left.accept().await {
+                Err(TransportError::Closed) => {
+                    debug!("Left transport closed");
+                    self.left_closed.store(true, Relaxed);
+                    None
+                }
+                result => Some(result.map(EitherConnection::Left)),
+            }
+        });
+        let mut right = pin!(async {
+            if self.right_closed.load(Relaxed) {
+                return None;
+            }
+            match self.right.accept().await {
+                Err(TransportError::Closed) => {
+                    debug!("Right transport closed");
+                    self.right_closed.store(true, Relaxed);
+                    None
+                }
+                result => Some(result.map(EitherConnection::Right)),
+            }
+        });
+
+        // Once one side is closed, keep waiting on the other
+        let result = match left_first {
+            true => tokio::select! {
+                biased;
+                Some(result) = &mut left => Some(result),
+                Some(result) = &mut right => Some(result),
+                else => None,
+            },
+            false => tokio::select! {
+                biased;
+                Some(result) = &mut right => Some(result),
+                Some(result) = &mut left => Some(result),
+                else => None,
+            },
+        };
+        result.unwrap_or(Err(TransportError::Closed))
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        match conn {
+            EitherConnection::Left(conn) => self.left.read(conn).await,
+            EitherConnection::Right(conn) => self.right.read(conn).await,
+        }
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        match conn {
+            EitherConnection::Left(conn) => self.left.write(conn, response).await,
+            EitherConnection::Right(conn) => self.right.write(conn, response).await,
+        }
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        match conn {
+            EitherConnection::Left(conn) => self.left.shutdown_conn(conn).await,
+            EitherConnection::Right(conn) => self.right.shutdown_conn(conn).await,
+        }
+    }
+
+    fn peer_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        match conn {
+            EitherConnection::Left(conn) => self.left.peer_addr(conn),
+            EitherConnection::Right(conn) => self.right.peer_addr(conn),
+        }
+    }
+
+    fn conn_local_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        match conn {
+            EitherConnection::Left(conn) => self.left.conn_local_addr(conn),
+            EitherConnection::Right(conn) => self.right.conn_local_addr(conn),
+        }
+    }
+
+    fn kind(&self, conn: &Self::Connection) -> &'static str {
+        match conn {
+            EitherConnection::Left(conn) => self.left.kind(conn),
+            EitherConnection::Right(conn) => self.right.kind(conn),
+        }
+    }
+
+    fn extensions(&self, conn: &Self::Connection) -> Extensions {
+        match conn {
+            EitherConnection::Left(conn) => self.left.extensions(conn),
+            EitherConnection::Right(conn) => self.right.extensions(conn),
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_reuse_port(&mut self, reuse_port: bool) -> TransportResult<()> {
+        self.left.set_reuse_port(reuse_port)?;
+        self.right.set_reuse_port(reuse_port)
+    }
+
+    #[cfg(unix)]
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        let mut listeners = self.left.listeners();
+        listeners.extend(self.right.listeners());
+        listeners
+    }
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        let left = self.left.shutdown().await;
+        let right = self.right.shutdown().await;
+        left.and(right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+        transport::{MemoryTransport, TcpTransport},
+        YarsServer,
+    };
+
+    #[tokio::test]
+    async fn accepts_on_every_address() {
+        let mut transport = MultiTransport::new(TcpTransport::new);
+        transport
+            .bind(["127.0.0.1:0", "127.0.0.1:0"].into())
+            .await
+            .unwrap();
+
+        for (index, bound) in transport.transports().iter().enumerate() {
+            let mut client = TcpStream::connect(bound.local_addr().unwrap())
+                .await
+                .unwrap();
+            client.write_all(b"ping").await.unwrap();
+
+            let mut conn = transport.accept().await.unwrap();
+            assert_eq!(conn.index, index);
+            assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_both_transports_with_same_router() {
+        let (left, left_client) = MemoryTransport::new();
+        let (right, right_client) = MemoryTransport::new();
+
+        let server = YarsServer::new(EitherTransport::new(left, right), HttpProtocol)
+            .get("/", async |_req: HttpRequest| -> crate::Result<_> {
+                Ok(HttpResponse::Ok().text("hi"))
+            })
+            .listen(((), ()));
+        let server = tokio::spawn(server);
+
+        for client in [&left_client, &right_client] {
+            let mut stream = client.connect().unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.ends_with("\r\n\r\nhi"));
+        }
+
+        // Server only stops once both transports are closed
+        drop(left_client);
+        tokio::task::yield_now().await;
+        assert!(!server.is_finished());
+
+        drop(right_client);
+        server.await.unwrap().unwrap();
+    }
+}
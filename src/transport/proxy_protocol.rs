@@ -0,0 +1,416 @@
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::BorrowedFd;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{debug, info, warn};
+
+mod parser;
+
+use super::{Cidr, Transport, TransportResult};
+use crate::{constants::MAX_REQUEST_SIZE, Extensions, TransportError};
+
+/// How much to read from the connection at a time while looking for the header
+const HEADER_CHUNK_SIZE: usize = 512;
+
+/// Implementation of the transport layer that reads a [PROXY protocol] (version 1 or 2) header
+/// from the start of every connection accepted by the wrapped transport, as sent by HAProxy,
+/// AWS Network Load Balancers and similar proxies.
+///
+/// The client address from the header replaces the proxy's address as the connection's peer
+/// address, see [`Transport::peer_addr`], and the header is attached to every request as a
+/// [ProxyHeader] extension. Connections that don't start with a valid header are closed.
+///
+/// To use TLS as well, wrap this transport in a [`TlsTransport`][super::TlsTransport], as the
+/// header is sent before the TLS handshake.
+///
+/// ```rust,no_run
+/// # async fn run() -> yars::Result<()> {
+/// use yars::{
+///     protocol::HttpProtocol,
+///     transport::{ProxyProtocolTransport, TcpTransport},
+///     YarsServer,
+/// };
+///
+/// let transport = ProxyProtocolTransport::new(TcpTransport::new())
+///     .trusted_proxies(["10.0.0.0/8".parse()?]);
+///
+/// YarsServer::new(transport, HttpProtocol)
+///     .listen("0.0.0.0:8080")
+///     .await
+/// # }
+/// ```
+///
+/// [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+pub struct ProxyProtocolTransport<T> {
+    inner: T,
+    trusted_proxies: Vec<Cidr>,
+}
+
+/// A PROXY protocol header, attached to every request read from a connection accepted by
+/// [`ProxyProtocolTransport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Version of the PROXY protocol used, 1 or 2
+    pub version: u8,
+    /// Address of the client that connected to the proxy.
+    ///
+    /// `None` if the proxy didn't forward a client, e.g. for its own health checks or for
+    /// clients that aren't connected over TCP/IP.
+    pub source: Option<SocketAddr>,
+    /// Address that the client connected to, which is usually the proxy's
+    pub destination: Option<SocketAddr>,
+}
+
+/// A connection accepted by [`ProxyProtocolTransport`]. The header is read when the connection
+/// is first read from.
+pub struct ProxyConnection<C> {
+    inner: C,
+    state: HeaderState,
+    header: Option<ProxyHeader>,
+}
+
+enum HeaderState {
+    /// Bytes read so far, which don't contain a complete header yet
+    Reading(Vec<u8>),
+    /// Bytes read after the header, which must be returned before reading any more
+    Leftover {
+        buf: Vec<u8>,
+        pos: usize,
+    },
+    Done,
+}
+
+impl<T> ProxyProtocolTransport<T>
+where
+    T: Transport,
+    T::Connection: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    /// Only accept connections from proxies with these addresses, other connections are closed
+    /// immediately. Otherwise a header from any peer is trusted.
+    pub fn trusted_proxies(mut self, proxies: impl IntoIterator<Item = Cidr>) -> Self {
+        self.trusted_proxies.extend(proxies);
+        self
+    }
+
+    /// The wrapped transport
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn is_trusted(&self, peer: Option<SocketAddr>) -> bool {
+        self.trusted_proxies.is_empty()
+            || peer.is_some_and(|peer| {
+                self.trusted_proxies
+                    .iter()
+                    .any(|cidr| cidr.contains(peer.ip()))
+            })
+    }
+}
+
+impl<C> ProxyConnection<C> {
+    fn new(inner: C) -> Self {
+        Self {
+            inner,
+            state: HeaderState::Reading(Vec::new()),
+            header: None,
+        }
+    }
+
+    /// The PROXY protocol header, once it has been read
+    pub fn header(&self) -> Option<&ProxyHeader> {
+        self.header.as_ref()
+    }
+}
+
+impl<C> ProxyConnection<C>
+where
+    C: AsyncRead + Unpin,
+{
+    /// Reads from the connection until the header has been parsed
+    fn poll_header(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let HeaderState::Reading(buf) = &mut self.state else {
+                return Poll::Ready(Ok(()));
+            };
+
+            match parser::parse_header(buf) {
+                Ok(Some((header, len))) => {
+                    debug!(?header, "Read PROXY protocol header");
+                    let leftover = buf.split_off(len);
+                    self.header = Some(header);
+                    self.state = HeaderState::Leftover {
+                        buf: leftover,
+                        pos: 0,
+                    };
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)))
+                }
+            }
+
+            let mut chunk = [0; HEADER_CHUNK_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before PROXY protocol header was received",
+                )));
+            }
+            buf.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<C> AsyncRead for ProxyConnection<C>
+where
+    C: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_header(cx))?;
+
+        if let HeaderState::Leftover { buf: leftover, pos } = &mut this.state {
+            let len = buf.remaining().min(leftover.len() - *pos);
+            buf.put_slice(&leftover[*pos..*pos + len]);
+            *pos += len;
+            if *pos == leftover.len() {
+                this.state = HeaderState::Done;
+            }
+            if len > 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<C> AsyncWrite for ProxyConnection<C>
+where
+    C: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<T> Transport for ProxyProtocolTransport<T>
+where
+    T: Transport,
+    T::Connection: AsyncRead + AsyncWrite + Unpin,
+{
+    type Addr = T::Addr;
+
+    type Connection = ProxyConnection<T::Connection>;
+
+    async fn bind(&mut self, local_addr: Self::Addr) -> TransportResult<()> {
+        self.inner.bind(local_addr).await?;
+        info!(
+            trusted_proxies = ?self.trusted_proxies,
+            "PROXY protocol enabled"
+        );
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        loop {
+            let conn = self.inner.accept().await?;
+
+            let peer = self.inner.peer_addr(&conn);
+            if !self.is_trusted(peer) {
+                // Dropping the connection closes it
+                warn!(?peer, "Rejected connection from untrusted proxy");
+                continue;
+            }
+
+            return Ok(ProxyConnection::new(conn));
+        }
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        poll_fn(|cx| conn.poll_header(cx))
+            .await
+            .map_err(|err| TransportError::ProxyProtocol(err.to_string()))?;
+
+        let mut buf = Vec::with_capacity(MAX_REQUEST_SIZE);
+        let len = conn.read_buf(&mut buf).await?;
+
+        debug!(len, "Successfully read from proxied connection");
+
+        Ok(buf)
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        debug!(len = response.len(), "Writing to proxied connection");
+        conn.write_all(response).await?;
+        conn.flush().await?;
+        Ok(())
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        self.inner.shutdown_conn(conn.inner).await
+    }
+
+    fn peer_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        conn.header
+            .as_ref()
+            .and_then(|header| header.source)
+            .or_else(|| self.inner.peer_addr(&conn.inner))
+    }
+
+    fn conn_local_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        conn.header
+            .as_ref()
+            .and_then(|header| header.destination)
+            .or_else(|| self.inner.conn_local_addr(&conn.inner))
+    }
+
+    fn kind(&self, conn: &Self::Connection) -> &'static str {
+        self.inner.kind(&conn.inner)
+    }
+
+    fn extensions(&self, conn: &Self::Connection) -> Extensions {
+        let mut extensions = self.inner.extensions(&conn.inner);
+        if let Some(header) = &conn.header {
+            extensions.insert(header.clone());
+        }
+        extensions
+    }
+
+    #[cfg(unix)]
+    fn set_reuse_port(&mut self, reuse_port: bool) -> TransportResult<()> {
+        self.inner.set_reuse_port(reuse_port)
+    }
+
+    #[cfg(unix)]
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        self.inner.listeners()
+    }
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+        transport::{MemoryTransport, TcpTransport},
+        YarsServer,
+    };
+
+    #[tokio::test]
+    async fn handlers_see_client_address_from_header() {
+        let (transport, client) = MemoryTransport::new();
+        let server = YarsServer::new(ProxyProtocolTransport::new(transport), HttpProtocol).get(
+            "/",
+            async |req: HttpRequest| -> crate::Result<_> {
+                let header = req.extensions.get::<ProxyHeader>().unwrap();
+                Ok(HttpResponse::Ok().text(header.source.unwrap().to_string()))
+            },
+        );
+        let server = tokio::spawn(server.listen(()));
+
+        let response = client
+            .send(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(response.ends_with(b"\r\n\r\n203.0.113.7:51234"));
+
+        // Malformed headers close the connection without a response
+        let response = client.send(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(response.is_empty());
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn header_can_arrive_over_multiple_reads() {
+        let (transport, client) = MemoryTransport::new();
+        let transport = ProxyProtocolTransport::new(transport);
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+
+        client_stream
+            .write_all(b"PROXY TCP6 2001:db8::1 ")
+            .await
+            .unwrap();
+        let read = tokio::spawn(async move {
+            let body = transport.read(&mut conn).await.unwrap();
+            (body, transport.peer_addr(&conn))
+        });
+        client_stream
+            .write_all(b"::1 4000 80\r\nping")
+            .await
+            .unwrap();
+
+        let (body, peer) = read.await.unwrap();
+        assert_eq!(body, b"ping");
+        assert_eq!(peer, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_from_untrusted_proxies() {
+        let mut transport = ProxyProtocolTransport::new(TcpTransport::new())
+            .trusted_proxies(["10.0.0.0/8".parse().unwrap()]);
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let addr = transport.inner().local_addr().unwrap();
+
+        let mut untrusted = TcpStream::connect(addr).await.unwrap();
+        let accept =
+            tokio::time::timeout(std::time::Duration::from_millis(100), transport.accept());
+        assert!(accept.await.is_err(), "untrusted connection was accepted");
+
+        // The connection was closed by the server
+        let mut buf = Vec::new();
+        assert_eq!(untrusted.read_to_end(&mut buf).await.unwrap(), 0);
+
+        let mut transport = ProxyProtocolTransport::new(TcpTransport::new())
+            .trusted_proxies(["127.0.0.0/8".parse().unwrap()]);
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let addr = transport.inner().local_addr().unwrap();
+
+        let _trusted = TcpStream::connect(addr).await.unwrap();
+        let conn = transport.accept().await.unwrap();
+        // Falls back to the proxy's address until the header has been read
+        assert!(transport.peer_addr(&conn).unwrap().ip().is_loopback());
+    }
+}
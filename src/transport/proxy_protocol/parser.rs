@@ -0,0 +1,340 @@
+//! Uses the [`nom`] parser combinator library to parse PROXY protocol headers.
+//!
+//! Streaming parsers are used because the header may arrive over multiple reads.
+//!
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+
+use nom::{
+    branch::alt,
+    bytes::streaming::{tag, take, take_until},
+    combinator::{map, map_res},
+    number::streaming::{be_u16, be_u8},
+    sequence::terminated,
+    IResult, Parser,
+};
+
+use super::ProxyHeader;
+use crate::constants::CRLF;
+
+/// Version 1 headers can be at most 107 bytes long, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Version 2 headers start with this signature, which can't be mistaken for version 1 or for
+/// any common protocol
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Parses a field terminated by `terminator`, e.g. an address or port
+fn field<'a, T: FromStr>(
+    terminator: &'static str,
+) -> impl Parser<&'a [u8], Output = T, Error = nom::error::Error<&'a [u8]>> {
+    terminated(
+        map_res(map_res(take_until(terminator), str::from_utf8), str::parse),
+        tag(terminator),
+    )
+}
+
+/// SRC_ADDR SP DST_ADDR SP SRC_PORT SP DST_PORT CRLF
+fn v1_addresses<A>(input: &[u8]) -> IResult<&[u8], ProxyHeader>
+where
+    A: FromStr + Into<std::net::IpAddr>,
+{
+    map(
+        (
+            field::<A>(" "),
+            field::<A>(" "),
+            field::<u16>(" "),
+            field::<u16>(CRLF),
+        ),
+        |(source, destination, source_port, destination_port)| ProxyHeader {
+            version: 1,
+            source: Some(SocketAddr::new(source.into(), source_port)),
+            destination: Some(SocketAddr::new(destination.into(), destination_port)),
+        },
+    )
+    .parse(input)
+}
+
+/// "PROXY" SP ("TCP4" | "TCP6") SP addresses, or "PROXY UNKNOWN" followed by anything
+fn v1(input: &[u8]) -> IResult<&[u8], ProxyHeader> {
+    let (input, _) = tag("PROXY ").parse(input)?;
+    alt((
+        |input| {
+            let (input, _) = tag("TCP4 ").parse(input)?;
+            v1_addresses::<Ipv4Addr>(input)
+        },
+        |input| {
+            let (input, _) = tag("TCP6 ").parse(input)?;
+            v1_addresses::<Ipv6Addr>(input)
+        },
+        map((tag("UNKNOWN"), take_until(CRLF), tag(CRLF)), |_| {
+            ProxyHeader {
+                version: 1,
+                source: None,
+                destination: None,
+            }
+        }),
+    ))
+    .parse(input)
+}
+
+/// SIGNATURE VER_CMD FAM LEN, followed by LEN bytes of addresses and TLVs
+fn v2(input: &[u8]) -> IResult<&[u8], (u8, u8, &[u8])> {
+    let (input, _) = tag(V2_SIGNATURE).parse(input)?;
+    let (input, (version_command, family)) = (be_u8, be_u8).parse(input)?;
+    let (input, len) = be_u16.parse(input)?;
+    let (input, payload) = take(len).parse(input)?;
+    Ok((input, (version_command, family, payload)))
+}
+
+/// Validates the fixed part of a version 2 header, and extracts the addresses from its payload.
+/// TLVs after the addresses are ignored.
+fn v2_header(version_command: u8, family: u8, payload: &[u8]) -> Result<ProxyHeader, String> {
+    if version_command >> 4 != 2 {
+        return Err(format!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        ));
+    }
+
+    let local = match version_command & 0x0F {
+        0x0 => true,
+        0x1 => false,
+        command => return Err(format!("Unknown PROXY protocol command {command:#x}")),
+    };
+
+    // High nibble is the address family, low nibble is the transport protocol
+    if family >> 4 > 0x3 || family & 0x0F > 0x2 {
+        return Err(format!(
+            "Unknown PROXY protocol address family {family:#04x}"
+        ));
+    }
+
+    let addresses = match family >> 4 {
+        // AF_INET
+        0x1 => {
+            let Some(addrs) = payload.get(..12) else {
+                return Err("PROXY protocol header too short for IPv4 addresses".into());
+            };
+            let ip = |i: usize| Ipv4Addr::new(addrs[i], addrs[i + 1], addrs[i + 2], addrs[i + 3]);
+            let port = |i: usize| u16::from_be_bytes([addrs[i], addrs[i + 1]]);
+            Some((
+                SocketAddr::new(ip(0).into(), port(8)),
+                SocketAddr::new(ip(4).into(), port(10)),
+            ))
+        }
+        // AF_INET6
+        0x2 => {
+            let Some(addrs) = payload.get(..36) else {
+                return Err("PROXY protocol header too short for IPv6 addresses".into());
+            };
+            let ip = |i: usize| {
+                let octets: [u8; 16] = addrs[i..i + 16].try_into().expect("slice is 16 bytes");
+                Ipv6Addr::from(octets)
+            };
+            let port = |i: usize| u16::from_be_bytes([addrs[i], addrs[i + 1]]);
+            Some((
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            ))
+        }
+        // AF_UNSPEC or AF_UNIX, neither of which has a socket address
+        _ => None,
+    };
+
+    // Connections made by the proxy itself (e.g. health checks) don't have a client
+    let (source, destination) = match addresses {
+        Some((source, destination)) if !local => (Some(source), Some(destination)),
+        _ => (None, None),
+    };
+
+    Ok(ProxyHeader {
+        version: 2,
+        source,
+        destination,
+    })
+}
+
+/// Parses the PROXY protocol header at the start of `input`.
+///
+/// Returns the header and its length, or `None` if more input is needed to parse it.
+pub(super) fn parse_header(input: &[u8]) -> Result<Option<(ProxyHeader, usize)>, String> {
+    let malformed = || "Malformed PROXY protocol header".to_string();
+
+    match v2(input) {
+        Ok((rest, (version_command, family, payload))) => {
+            let header = v2_header(version_command, family, payload)?;
+            return Ok(Some((header, input.len() - rest.len())));
+        }
+        Err(nom::Err::Incomplete(_)) => return Ok(None),
+        // Not version 2, try version 1
+        Err(_) => {}
+    }
+
+    match v1(input) {
+        Ok((rest, header)) => {
+            let len = input.len() - rest.len();
+            if len > V1_MAX_LEN {
+                return Err(malformed());
+            }
+            Ok(Some((header, len)))
+        }
+        Err(nom::Err::Incomplete(_)) if input.len() < V1_MAX_LEN => Ok(None),
+        Err(_) => Err(malformed()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version: u8, source: Option<&str>, destination: Option<&str>) -> Option<ProxyHeader> {
+        Some(ProxyHeader {
+            version,
+            source: source.map(|addr| addr.parse().unwrap()),
+            destination: destination.map(|addr| addr.parse().unwrap()),
+        })
+    }
+
+    #[test]
+    fn parses_v1_tcp4() {
+        let input = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (parsed, len) = parse_header(input).unwrap().unwrap();
+
+        assert_eq!(
+            Some(parsed),
+            header(1, Some("192.168.0.1:56324"), Some("192.168.0.11:443"))
+        );
+        assert_eq!(&input[len..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn parses_v1_tcp6() {
+        let input = b"PROXY TCP6 2001:db8::1 ::1 4000 80\r\n";
+        assert_eq!(
+            parse_header(input).unwrap().map(|(header, _)| header),
+            header(1, Some("[2001:db8::1]:4000"), Some("[::1]:80"))
+        );
+    }
+
+    #[test]
+    fn parses_v1_unknown() {
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n")
+                .unwrap()
+                .map(|(header, _)| header),
+            header(1, None, None)
+        );
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN\r\n"),
+            Ok(header(1, None, None).map(|header| (header, 15)))
+        );
+    }
+
+    #[test]
+    fn needs_more_input_for_partial_headers() {
+        assert_eq!(parse_header(b""), Ok(None));
+        assert_eq!(parse_header(b"PRO"), Ok(None));
+        assert_eq!(parse_header(b"PROXY TCP4 192.168.0.1 19"), Ok(None));
+        assert_eq!(parse_header(&V2_SIGNATURE[..5]), Ok(None));
+        assert_eq!(parse_header(&v2_proxy_tcp4()[..20]), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_v1() {
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP5 1.1.1.1 2.2.2.2 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 1.1.1 2.2.2.2 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 ::1 ::2 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 1.1.1.1 2.2.2.2 1 65536\r\n").is_err());
+
+        // Never terminated
+        let mut too_long = b"PROXY UNKNOWN ".to_vec();
+        too_long.resize(V1_MAX_LEN, b'a');
+        assert!(parse_header(&too_long).is_err());
+    }
+
+    fn v2_proxy_tcp4() -> Vec<u8> {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x11, 0, 12]);
+        input.extend([10, 0, 0, 1, 10, 0, 0, 2]);
+        input.extend(1234u16.to_be_bytes());
+        input.extend(80u16.to_be_bytes());
+        input
+    }
+
+    #[test]
+    fn parses_v2_tcp4() {
+        let mut input = v2_proxy_tcp4();
+        input.extend(b"GET / HTTP/1.1\r\n");
+        let (parsed, len) = parse_header(&input).unwrap().unwrap();
+
+        assert_eq!(
+            Some(parsed),
+            header(2, Some("10.0.0.1:1234"), Some("10.0.0.2:80"))
+        );
+        assert_eq!(&input[len..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn parses_v2_tcp6_with_tlvs() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x21, 0, 36 + 7]);
+        input.extend(Ipv6Addr::LOCALHOST.octets());
+        input.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        input.extend(1234u16.to_be_bytes());
+        input.extend(443u16.to_be_bytes());
+        // PP2_TYPE_AUTHORITY TLV
+        input.extend([0x02, 0, 4]);
+        input.extend(b"host");
+
+        let (parsed, len) = parse_header(&input).unwrap().unwrap();
+        assert_eq!(
+            Some(parsed),
+            header(2, Some("[::1]:1234"), Some("[2001:db8::2]:443"))
+        );
+        assert_eq!(len, input.len());
+    }
+
+    #[test]
+    fn parses_v2_local_without_addresses() {
+        let mut input = v2_proxy_tcp4();
+        input[12] = 0x20;
+        assert_eq!(
+            parse_header(&input).unwrap().map(|(header, _)| header),
+            header(2, None, None)
+        );
+
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(
+            parse_header(&input).unwrap().map(|(header, _)| header),
+            header(2, None, None)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_v2() {
+        // Version 3
+        let mut input = v2_proxy_tcp4();
+        input[12] = 0x31;
+        assert!(parse_header(&input).is_err());
+
+        // Unknown command
+        let mut input = v2_proxy_tcp4();
+        input[12] = 0x22;
+        assert!(parse_header(&input).is_err());
+
+        // Unknown address family
+        let mut input = v2_proxy_tcp4();
+        input[13] = 0x41;
+        assert!(parse_header(&input).is_err());
+
+        // Too short for the addresses
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x11, 0, 4, 10, 0, 0, 1]);
+        assert!(parse_header(&input).is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use quinn::{
+    crypto::rustls::{HandshakeData, QuicServerConfig},
+    default_runtime, Connection, Endpoint, EndpointConfig, Incoming, RecvStream, SendStream,
+    ServerConfig,
+};
+use socket2::Type;
+use tokio::sync::{mpsc, Mutex};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tracing::{debug, info, Instrument};
+
+#[cfg(unix)]
+use super::listen_fds;
+use super::{
+    socket_addrs::bind_socket, PeerCertificate, SocketAddrs, TlsConfig, TlsInfo, Transport,
+    TransportResult,
+};
+use crate::{
+    constants::{ACCEPT_QUEUE_LEN, MAX_REQUEST_SIZE},
+    Extensions, TransportError,
+};
+
+/// Implementation of the transport layer for QUIC, where every bidirectional stream that a client
+/// opens is treated as its own connection.
+///
+/// Many requests can be in flight on a single QUIC connection without head-of-line blocking, and
+/// a client keeps its QUIC connection if its address changes (connection migration), so
+/// [`peer_addr`][Transport::peer_addr] is the client's address at the time of asking.
+///
+/// Streams are queued as they are opened. Once the queue is full, e.g. because the server has
+/// reached [`max_connections`][crate::YarsServer::max_connections], no more streams are accepted
+/// until it catches up, and clients are held back by QUIC's limit on concurrent streams.
+///
+/// TLS is built into QUIC, so the transport is configured with a [`TlsConfig`] like
+/// [`TlsTransport`][super::TlsTransport]. Only TLS 1.3 is supported. [`TlsInfo`] and
+/// [`PeerCertificate`] extensions are attached to requests in the same way.
+///
+/// Requires the `quic` feature.
+pub struct QuicTransport {
+    server_config: ServerConfig,
+    endpoint: Option<Endpoint>,
+    /// Streams accepted from all connections. Closed once the endpoint has been closed.
+    streams: Mutex<mpsc::Receiver<QuicStream>>,
+    /// Taken by the task accepting connections when the transport is bound
+    streams_tx: Option<mpsc::Sender<QuicStream>>,
+}
+
+/// A bidirectional stream accepted by [`QuicTransport`], along with the QUIC connection it
+/// belongs to
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    connection: Connection,
+}
+
+impl QuicStream {
+    /// The QUIC connection that the stream belongs to, which other streams from the same client
+    /// share
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+fn quic_error(err: impl std::fmt::Display) -> TransportError {
+    TransportError::Quic(err.to_string())
+}
+
+impl QuicTransport {
+    pub fn new(config: TlsConfig) -> TransportResult<Self> {
+        Self::with_alpn_protocols(config, Vec::new())
+    }
+
+    /// Like [`new`][Self::new], only accepting clients that negotiate one of `protocols` using
+    /// ALPN, e.g. `[b"my-protocol".to_vec()]`
+    pub fn with_alpn_protocols(
+        config: TlsConfig,
+        protocols: Vec<Vec<u8>>,
+    ) -> TransportResult<Self> {
+        let mut tls = config.into_server_config()?;
+        tls.alpn_protocols = protocols;
+        let crypto = QuicServerConfig::try_from(tls).map_err(quic_error)?;
+
+        let (streams_tx, streams) = mpsc::channel(ACCEPT_QUEUE_LEN);
+        Ok(Self {
+            server_config: ServerConfig::with_crypto(Arc::new(crypto)),
+            endpoint: None,
+            streams: Mutex::new(streams),
+            streams_tx: Some(streams_tx),
+        })
+    }
+
+    /// The local address that the transport is bound to
+    pub fn local_addr(&self) -> TransportResult<SocketAddr> {
+        Ok(self.endpoint()?.local_addr()?)
+    }
+
+    fn endpoint(&self) -> TransportResult<&Endpoint> {
+        // Error should never happen because this should only be used internally
+        self.endpoint.as_ref().ok_or(TransportError::Quic(
+            "QUIC endpoint not bound. Call `bind` first.".into(),
+        ))
+    }
+}
+
+/// Accepts QUIC connections until the endpoint is closed
+async fn accept_connections(endpoint: Endpoint, streams: mpsc::Sender<QuicStream>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let span = tracing::debug_span!("quic_connection", peer = %incoming.remote_address());
+        tokio::spawn(accept_streams(incoming, streams.clone()).instrument(span));
+    }
+}
+
+/// Completes the handshake, then accepts streams until the connection is closed. Done in its own
+/// task, so a slow client doesn't hold up other clients.
+///
+/// Waits for room in the queue before accepting the next stream, so clients can't open more
+/// streams than the server takes.
+async fn accept_streams(incoming: Incoming, streams: mpsc::Sender<QuicStream>) {
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            debug!(%err, "QUIC handshake failed");
+            return;
+        }
+    };
+    debug!("QUIC handshake complete");
+
+    loop {
+        match connection.accept_bi().await {
+            Ok((send, recv)) => {
+                let stream = QuicStream {
+                    send,
+                    recv,
+                    connection: connection.clone(),
+                };
+                if streams.send(stream).await.is_err() {
+                    // Transport has been shut down
+                    return;
+                }
+            }
+            Err(err) => {
+                debug!(%err, "QUIC connection closed");
+                return;
+            }
+        }
+    }
+}
+
+impl Transport for QuicTransport {
+    type Addr = SocketAddrs;
+
+    type Connection = QuicStream;
+
+    async fn bind(&mut self, local_addr: SocketAddrs) -> TransportResult<()> {
+        let socket: std::net::UdpSocket = match local_addr {
+            #[cfg(unix)]
+            SocketAddrs::Systemd(listen_fd) => listen_fd.take()?.into(),
+            local_addr => {
+                let addrs = local_addr.resolve().await?;
+                // Already bound by systemd
+                #[cfg(unix)]
+                let inherited = listen_fds::take_socket_bound_to(Type::DGRAM, &addrs);
+                #[cfg(not(unix))]
+                let inherited = None;
+                match inherited {
+                    Some(fd) => fd.into(),
+                    None => bind_socket(&addrs, Type::DGRAM, |_, _| Ok(()))?.into(),
+                }
+            }
+        };
+
+        let runtime = default_runtime().ok_or_else(|| quic_error("No async runtime found"))?;
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(self.server_config.clone()),
+            socket,
+            runtime,
+        )?;
+        info!(
+            "Listening for QUIC connections on {}",
+            endpoint.local_addr()?
+        );
+
+        let streams = self
+            .streams_tx
+            .take()
+            .ok_or_else(|| quic_error("QUIC endpoint already bound"))?;
+        tokio::spawn(accept_connections(endpoint.clone(), streams));
+        self.endpoint = Some(endpoint);
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        // Make sure the transport has been bound, otherwise this would wait forever
+        self.endpoint()?;
+        let stream = self
+            .streams
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(TransportError::Closed)?;
+        debug!(
+            peer = %stream.connection.remote_address(),
+            id = %stream.send.id(),
+            "Accepted QUIC stream"
+        );
+        Ok(stream)
+    }
+
+    async fn read(&self, stream: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        let mut buf = vec![0; MAX_REQUEST_SIZE];
+        let len = stream
+            .recv
+            .read(&mut buf)
+            .await
+            .map_err(quic_error)?
+            // The client has finished sending
+            .unwrap_or(0);
+        buf.truncate(len);
+
+        debug!(len, "Successfully read from QUIC stream");
+        Ok(buf)
+    }
+
+    async fn write(&self, stream: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        debug!(len = response.len(), "Writing to QUIC stream");
+        stream.send.write_all(response).await.map_err(quic_error)
+    }
+
+    async fn shutdown_conn(&self, mut stream: Self::Connection) -> TransportResult<()> {
+        // Only finishes the stream, the QUIC connection stays open for other streams
+        stream.send.finish().map_err(quic_error)
+    }
+
+    fn peer_addr(&self, stream: &Self::Connection) -> Option<SocketAddr> {
+        Some(stream.connection.remote_address())
+    }
+
+    fn conn_local_addr(&self, _stream: &Self::Connection) -> Option<SocketAddr> {
+        self.local_addr().ok()
+    }
+
+    fn kind(&self, _stream: &Self::Connection) -> &'static str {
+        "quic"
+    }
+
+    fn extensions(&self, stream: &Self::Connection) -> Extensions {
+        let mut extensions = Extensions::new();
+
+        if let Some(handshake) = stream
+            .connection
+            .handshake_data()
+            .and_then(|data| data.downcast::<HandshakeData>().ok())
+        {
+            extensions.insert(TlsInfo {
+                server_name: handshake.server_name,
+            });
+        }
+
+        // The end-entity certificate comes first
+        if let Some(cert) = stream
+            .connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|certs| {
+                certs
+                    .first()
+                    .and_then(|cert| PeerCertificate::from_der(cert))
+            })
+        {
+            extensions.insert(cert);
+        }
+
+        extensions
+    }
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        let endpoint = self.endpoint()?;
+        endpoint.close(0u32.into(), b"server shutting down");
+        endpoint.wait_idle().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn::{crypto::rustls::QuicClientConfig, ClientConfig};
+    use tokio_rustls::rustls::{
+        crypto::ring, version::TLS13, ClientConfig as RustlsClientConfig, RootCertStore,
+    };
+
+    use super::*;
+    use crate::transport::SelfSignedCert;
+
+    async fn bound_transport(cert: &SelfSignedCert) -> QuicTransport {
+        let config = TlsConfig::new()
+            .cert_pem(cert.cert_pem.as_bytes(), cert.key_pem.as_bytes())
+            .unwrap();
+        let mut transport =
+            QuicTransport::with_alpn_protocols(config, vec![b"yars-test".to_vec()]).unwrap();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        transport
+    }
+
+    async fn connect(server_addr: SocketAddr, cert: &SelfSignedCert) -> (Endpoint, Connection) {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert_der().to_vec().into()).unwrap();
+        let mut tls = RustlsClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[&TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"yars-test".to_vec()];
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls).unwrap(),
+        )));
+        let connection = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        (client, connection)
+    }
+
+    #[tokio::test]
+    async fn treats_each_stream_as_a_connection() {
+        let cert = SelfSignedCert::generate(["localhost".to_string()]).unwrap();
+        let transport = bound_transport(&cert).await;
+        let (client, connection) = connect(transport.local_addr().unwrap(), &cert).await;
+
+        for request in [&b"first"[..], b"second"] {
+            let (mut send, mut recv) = connection.open_bi().await.unwrap();
+            send.write_all(request).await.unwrap();
+            send.finish().unwrap();
+
+            let mut stream = transport.accept().await.unwrap();
+            assert_eq!(
+                transport.peer_addr(&stream),
+                Some(client.local_addr().unwrap())
+            );
+            let extensions = transport.extensions(&stream);
+            assert_eq!(
+                extensions.get::<TlsInfo>().unwrap().server_name.as_deref(),
+                Some("localhost")
+            );
+
+            assert_eq!(transport.read(&mut stream).await.unwrap(), request);
+            // Client has finished sending
+            assert!(transport.read(&mut stream).await.unwrap().is_empty());
+            transport.write(&mut stream, b"response").await.unwrap();
+            transport.shutdown_conn(stream).await.unwrap();
+
+            assert_eq!(recv.read_to_end(64).await.unwrap(), b"response");
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_after_shutdown() {
+        let cert = SelfSignedCert::generate(["localhost".to_string()]).unwrap();
+        let transport = bound_transport(&cert).await;
+        let (_client, connection) = connect(transport.local_addr().unwrap(), &cert).await;
+
+        transport.shutdown().await.unwrap();
+        assert!(matches!(
+            transport.accept().await,
+            Err(TransportError::Closed)
+        ));
+        assert!(connection.open_bi().await.is_err());
+    }
+}
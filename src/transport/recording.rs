@@ -0,0 +1,528 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+use tracing::{info, warn};
+
+use super::{delegate_to_inner, lock_ignoring_poison, MemoryTransport, Transport, TransportResult};
+use crate::{protocol::Protocol, TransportError, YarsServer};
+
+/// First line of a capture file, so that other files aren't mistaken for captures
+const HEADER: &str = "# yars capture v1";
+
+/// How long to wait for the server's response to each replayed connection
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wraps any transport, recording every connection's inbound and outbound bytes to a capture
+/// file, so that traffic can later be replayed with [`Capture::replay`], e.g. as regression
+/// fixtures.
+///
+/// The capture file is a text file with a line per event: the connection ID, the time in
+/// microseconds since the Unix epoch, then `open` with the peer address, `in` or `out` with the
+/// hex encoded bytes, or `close`.
+///
+/// Failing to write to the capture file is logged, but doesn't affect the connection.
+///
+/// ```rust,no_run
+/// # fn main() -> yars::transport::TransportResult<()> {
+/// use yars::transport::{RecordingTransport, TcpTransport};
+///
+/// let transport = RecordingTransport::new(TcpTransport::new(), "staging.capture")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RecordingTransport<T> {
+    inner: T,
+    capture: Mutex<BufWriter<File>>,
+    next_id: AtomicU64,
+}
+
+/// Connection accepted by [`RecordingTransport`]
+pub struct RecordingConnection<C> {
+    inner: C,
+    /// ID of the connection in the capture file
+    id: u64,
+}
+
+impl<C> RecordingConnection<C> {
+    /// The wrapped connection
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+fn capture_error(message: impl fmt::Display) -> TransportError {
+    TransportError::Generic(format!("Capture file: {message}"))
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Records to the file at `path`, replacing it if it already exists
+    pub fn new(inner: T, path: impl AsRef<Path>) -> TransportResult<Self> {
+        let mut capture = BufWriter::new(File::create(path)?);
+        writeln!(capture, "{HEADER}")?;
+        Ok(Self {
+            inner,
+            capture: Mutex::new(capture),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// The wrapped transport
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn record(&self, id: u64, event: fmt::Arguments, flush: bool) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        // Formatted before locking, so that a panic while formatting can't leave half a line in
+        // the capture
+        let line = format!("{id} {at} {event}\n");
+        let mut capture = lock_ignoring_poison(&self.capture);
+        let result = capture
+            .write_all(line.as_bytes())
+            .and_then(|()| match flush {
+                true => capture.flush(),
+                false => Ok(()),
+            });
+        if let Err(err) = result {
+            warn!(?err, "Failed to write to capture file");
+        }
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    type Addr = T::Addr;
+
+    type Connection = RecordingConnection<T::Connection>;
+
+    async fn bind(&mut self, local_addr: Self::Addr) -> TransportResult<()> {
+        self.inner.bind(local_addr).await?;
+        info!("Recording connections to capture file");
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let inner = self.inner.accept().await?;
+        let id = self.next_id.fetch_add(1, Relaxed);
+        match self.inner.peer_addr(&inner) {
+            Some(peer) => self.record(id, format_args!("open {peer}"), false),
+            None => self.record(id, format_args!("open -"), false),
+        }
+        Ok(RecordingConnection { inner, id })
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        let data = self.inner.read(&mut conn.inner).await?;
+        if !data.is_empty() {
+            self.record(conn.id, format_args!("in {}", Hex(&data)), false);
+        }
+        Ok(data)
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        self.inner.write(&mut conn.inner, response).await?;
+        self.record(conn.id, format_args!("out {}", Hex(response)), false);
+        Ok(())
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        self.record(conn.id, format_args!("close"), true);
+        self.inner.shutdown_conn(conn.inner).await
+    }
+
+    delegate_to_inner!(
+        peer_addr,
+        conn_local_addr,
+        kind,
+        extensions,
+        listeners,
+        set_reuse_port
+    );
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        if let Err(err) = lock_ignoring_poison(&self.capture).flush() {
+            warn!(?err, "Failed to flush capture file");
+        }
+        self.inner.shutdown().await
+    }
+}
+
+/// Formats bytes as lowercase hex
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Connections recorded by [`RecordingTransport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    connections: Vec<RecordedConnection>,
+}
+
+/// A connection recorded by [`RecordingTransport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedConnection {
+    /// ID of the connection in the capture file
+    pub id: u64,
+    /// Address of the client, if the transport knew it
+    pub peer: Option<SocketAddr>,
+    /// Bytes read and written, in order
+    pub events: Vec<RecordedEvent>,
+}
+
+/// Bytes read from or written to a [`RecordedConnection`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedEvent {
+    pub at: SystemTime,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the client
+    Inbound,
+    /// Written to the client
+    Outbound,
+}
+
+impl RecordedConnection {
+    /// All bytes flowing in `direction`
+    pub fn bytes(&self, direction: Direction) -> Vec<u8> {
+        self.events
+            .iter()
+            .filter(|event| event.direction == direction)
+            .flat_map(|event| event.data.iter().copied())
+            .collect()
+    }
+}
+
+impl Capture {
+    /// Reads a capture file written by [`RecordingTransport`]
+    pub fn read(path: impl AsRef<Path>) -> TransportResult<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(capture_error("not a yars capture file"));
+        }
+
+        let mut connections: Vec<RecordedConnection> = Vec::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            // The header is line 1
+            let invalid = || capture_error(format!("invalid line {}: {line:?}", index + 2));
+
+            let mut parts = line.split(' ');
+            let (Some(id), Some(at), Some(kind)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid());
+            };
+            let id: u64 = id.parse().map_err(|_| invalid())?;
+            let at: u64 = at.parse().map_err(|_| invalid())?;
+            let at = UNIX_EPOCH + Duration::from_micros(at);
+            let arg = parts.next();
+
+            let direction = match (kind, arg) {
+                ("open", Some(peer)) => {
+                    connections.push(RecordedConnection {
+                        id,
+                        peer: peer.parse().ok(),
+                        events: Vec::new(),
+                    });
+                    continue;
+                }
+                ("close", None) => continue,
+                ("in", Some(_)) => Direction::Inbound,
+                ("out", Some(_)) => Direction::Outbound,
+                _ => return Err(invalid()),
+            };
+            let data = arg.and_then(parse_hex).ok_or_else(invalid)?;
+            let conn = connections
+                .iter_mut()
+                .rfind(|conn| conn.id == id)
+                .ok_or_else(invalid)?;
+            conn.events.push(RecordedEvent {
+                at,
+                direction,
+                data,
+            });
+        }
+
+        Ok(Self { connections })
+    }
+
+    /// The recorded connections, in the order they were accepted
+    pub fn connections(&self) -> &[RecordedConnection] {
+        &self.connections
+    }
+
+    /// Replays every recorded connection against the server built by `make_server`, one at a
+    /// time, and compares its responses with the recorded ones.
+    ///
+    /// All of a connection's recorded inbound bytes are sent before its response is read. Errors
+    /// sending or reading them, e.g. because the server closed the connection early, make that
+    /// connection a mismatch rather than failing the replay.
+    ///
+    /// ```rust,no_run
+    /// # async fn run() -> yars::Result<()> {
+    /// use yars::{protocol::HttpProtocol, transport::Capture, YarsServer};
+    /// # async fn hello(_req: yars::http::HttpRequest) -> yars::Result<yars::http::HttpResponse> { todo!() }
+    ///
+    /// let report = Capture::read("staging.capture")?
+    ///     .replay(|transport| YarsServer::new(transport, HttpProtocol).get("/", hello))
+    ///     .await?;
+    /// assert!(report.is_success(), "{report}");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn replay<P>(
+        &self,
+        make_server: impl FnOnce(MemoryTransport) -> YarsServer<MemoryTransport, P>,
+    ) -> crate::Result<ReplayReport>
+    where
+        P: Protocol,
+    {
+        let (transport, client) = MemoryTransport::new();
+        let server = tokio::spawn(make_server(transport).listen(()));
+
+        let mut report = ReplayReport {
+            connections: self.connections.len(),
+            mismatches: Vec::new(),
+        };
+        for conn in &self.connections {
+            let mut stream = client.connect()?;
+            let mut actual = Vec::new();
+            let exchanged = timeout(REPLAY_TIMEOUT, async {
+                // Whatever the server sent before an error is still compared
+                let written = stream.write_all(&conn.bytes(Direction::Inbound)).await;
+                let read = stream.read_to_end(&mut actual).await;
+                written.and(read.map(drop))
+            })
+            .await;
+            let (timed_out, error) = match exchanged {
+                Ok(result) => (false, result.err().map(|err| err.to_string())),
+                Err(_) => (true, None),
+            };
+
+            let expected = conn.bytes(Direction::Outbound);
+            if timed_out || error.is_some() || actual != expected {
+                report.mismatches.push(ReplayMismatch {
+                    id: conn.id,
+                    expected,
+                    actual,
+                    timed_out,
+                    error,
+                });
+            }
+        }
+
+        // The server stops once the client has been dropped
+        drop(client);
+        server
+            .await
+            .map_err(|err| TransportError::Generic(err.to_string()))??;
+        Ok(report)
+    }
+}
+
+/// Result of [`Capture::replay`]. Its [`Display`][fmt::Display] implementation describes every
+/// mismatch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Number of connections replayed
+    pub connections: usize,
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+/// A replayed connection whose response differs from the recorded one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayMismatch {
+    /// ID of the connection in the capture file
+    pub id: u64,
+    /// Recorded response
+    pub expected: Vec<u8>,
+    /// Response from the replay
+    pub actual: Vec<u8>,
+    /// The server didn't close the connection in time, so `actual` may be incomplete
+    pub timed_out: bool,
+    /// Error sending the recorded request or reading the response, e.g. because the server
+    /// closed the connection before reading all of the request
+    pub error: Option<String>,
+}
+
+impl ReplayReport {
+    /// Whether every response matched the recording
+    pub fn is_success(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} replayed connections matched",
+            self.connections - self.mismatches.len(),
+            self.connections
+        )?;
+        for mismatch in &self.mismatches {
+            write!(f, "\n{mismatch}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ReplayMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self
+            .expected
+            .iter()
+            .zip(&self.actual)
+            .position(|(expected, actual)| expected != actual)
+            .unwrap_or(self.expected.len().min(self.actual.len()));
+        write!(f, "connection {}: ", self.id)?;
+        if self.timed_out {
+            write!(f, "timed out, ")?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, "{error}, ")?;
+        }
+        match self.expected == self.actual {
+            true => writeln!(f, "responses match")?,
+            false => writeln!(f, "responses differ at byte {offset}")?,
+        }
+        writeln!(f, "  expected: \"{}\"", self.expected.escape_ascii())?;
+        write!(f, "  actual:   \"{}\"", self.actual.escape_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+    };
+
+    fn capture_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("yars-{name}-{}.capture", std::process::id()))
+    }
+
+    async fn greeting(_req: HttpRequest) -> crate::Result<HttpResponse> {
+        Ok(HttpResponse::Ok().text("Hello"))
+    }
+
+    async fn changed_greeting(_req: HttpRequest) -> crate::Result<HttpResponse> {
+        Ok(HttpResponse::Ok().text("Goodbye"))
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = b"\x00\x7fGET /\r\n\xff";
+        assert_eq!(parse_hex(&Hex(bytes).to_string()).unwrap(), bytes);
+        assert_eq!(parse_hex("abc"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_traffic() {
+        let path = capture_path("replay");
+        let (inner, client) = MemoryTransport::new();
+        let transport = RecordingTransport::new(inner, &path).unwrap();
+        let server = tokio::spawn(
+            YarsServer::new(transport, HttpProtocol)
+                .get("/", greeting)
+                .listen(()),
+        );
+
+        let response = client.send(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.send(b"GET /missing HTTP/1.1\r\n\r\n").await.unwrap();
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        let capture = Capture::read(&path).unwrap();
+        let connections = capture.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(
+            connections[0].bytes(Direction::Inbound),
+            b"GET / HTTP/1.1\r\n\r\n"
+        );
+        assert_eq!(connections[0].bytes(Direction::Outbound), response);
+        assert!(connections[1].bytes(Direction::Outbound).is_empty());
+
+        let report = capture
+            .replay(|transport| YarsServer::new(transport, HttpProtocol).get("/", greeting))
+            .await
+            .unwrap();
+        assert!(report.is_success(), "{report}");
+        assert_eq!(report.connections, 2);
+
+        let report = capture
+            .replay(|transport| YarsServer::new(transport, HttpProtocol).get("/", changed_greeting))
+            .await
+            .unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].id, connections[0].id);
+        assert!(report.to_string().contains("Goodbye"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_connections_closed_early_as_mismatches() {
+        let path = capture_path("closed-early");
+        // Far more than the server reads before giving up on the request
+        let request = "ff".repeat(256 * 1024);
+        std::fs::write(
+            &path,
+            format!("{HEADER}\n0 0 open -\n0 0 in {request}\n0 0 close\n"),
+        )
+        .unwrap();
+
+        let report = Capture::read(&path)
+            .unwrap()
+            .replay(|transport| YarsServer::new(transport, HttpProtocol).get("/", greeting))
+            .await
+            .unwrap();
+        assert_eq!(report.mismatches.len(), 1, "{report}");
+        assert!(report.mismatches[0].error.is_some(), "{report}");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = capture_path("invalid");
+        std::fs::write(&path, "not a capture\n").unwrap();
+        assert!(Capture::read(&path).is_err());
+
+        std::fs::write(&path, format!("{HEADER}\n0 0 in 00\n")).unwrap();
+        // Bytes for a connection that was never opened
+        assert!(Capture::read(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
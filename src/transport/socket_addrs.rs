@@ -0,0 +1,151 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use socket2::{Domain, Socket, Type};
+use tokio::net::lookup_host;
+
+#[cfg(unix)]
+use super::ListenFd;
+
+/// Socket address(es) for transports that bind to IP addresses, such as
+/// [`TcpTransport`][super::TcpTransport] and [`UdpTransport`][super::UdpTransport].
+///
+/// Can be created from anything that looks like a socket address, e.g. `"127.0.0.1:8000"`,
+/// `("localhost", 8000)` or a [`SocketAddr`]. Host names are resolved when the transport binds.
+///
+/// On Unix, can also be a socket passed by systemd, which is adopted instead of binding a new one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketAddrs {
+    /// A `host:port` string, or a `(host, port)` pair, that needs to be resolved
+    Host(String),
+    /// Already resolved socket addresses
+    Resolved(Vec<SocketAddr>),
+    /// An already bound socket passed by systemd socket activation
+    #[cfg(unix)]
+    Systemd(ListenFd),
+}
+
+impl SocketAddrs {
+    /// Resolves the socket addresses. The transport should try to bind to each in turn.
+    pub(crate) async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Self::Host(host) => Ok(lookup_host(host.as_str()).await?.collect()),
+            Self::Resolved(addrs) => Ok(addrs.clone()),
+            #[cfg(unix)]
+            Self::Systemd(listen_fd) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("systemd socket {listen_fd} should be adopted, not resolved"),
+            )),
+        }
+    }
+}
+
+/// Binds a new non-blocking socket of type `ty`, letting `configure` set its options first (e.g.
+/// `SO_REUSEPORT`, so that other processes can bind to the same address and share its traffic).
+/// Tries each of `addrs` in turn.
+pub(crate) fn bind_socket(
+    addrs: &[SocketAddr],
+    ty: Type,
+    configure: impl Fn(&Socket, SocketAddr) -> io::Result<()>,
+) -> io::Result<Socket> {
+    let bind = |addr: SocketAddr| {
+        let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+        configure(&socket, addr)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        Ok(socket)
+    };
+
+    let mut last_err = None;
+    for &addr in addrs {
+        match bind(addr) {
+            Ok(socket) => return Ok(socket),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+impl From<&str> for SocketAddrs {
+    fn from(host: &str) -> Self {
+        Self::Host(host.to_string())
+    }
+}
+
+impl From<String> for SocketAddrs {
+    fn from(host: String) -> Self {
+        Self::Host(host)
+    }
+}
+
+impl From<(&str, u16)> for SocketAddrs {
+    fn from((host, port): (&str, u16)) -> Self {
+        Self::Host(format!("{host}:{port}"))
+    }
+}
+
+impl From<(String, u16)> for SocketAddrs {
+    fn from((host, port): (String, u16)) -> Self {
+        Self::Host(format!("{host}:{port}"))
+    }
+}
+
+impl From<SocketAddr> for SocketAddrs {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Resolved(vec![addr])
+    }
+}
+
+impl From<SocketAddrV4> for SocketAddrs {
+    fn from(addr: SocketAddrV4) -> Self {
+        SocketAddr::V4(addr).into()
+    }
+}
+
+impl From<SocketAddrV6> for SocketAddrs {
+    fn from(addr: SocketAddrV6) -> Self {
+        SocketAddr::V6(addr).into()
+    }
+}
+
+impl From<(IpAddr, u16)> for SocketAddrs {
+    fn from(addr: (IpAddr, u16)) -> Self {
+        SocketAddr::from(addr).into()
+    }
+}
+
+impl From<(Ipv4Addr, u16)> for SocketAddrs {
+    fn from(addr: (Ipv4Addr, u16)) -> Self {
+        SocketAddr::from(addr).into()
+    }
+}
+
+impl From<(Ipv6Addr, u16)> for SocketAddrs {
+    fn from(addr: (Ipv6Addr, u16)) -> Self {
+        SocketAddr::from(addr).into()
+    }
+}
+
+impl From<Vec<SocketAddr>> for SocketAddrs {
+    fn from(addrs: Vec<SocketAddr>) -> Self {
+        Self::Resolved(addrs)
+    }
+}
+
+impl From<&[SocketAddr]> for SocketAddrs {
+    fn from(addrs: &[SocketAddr]) -> Self {
+        Self::Resolved(addrs.to_vec())
+    }
+}
+
+#[cfg(unix)]
+impl From<ListenFd> for SocketAddrs {
+    fn from(listen_fd: ListenFd) -> Self {
+        Self::Systemd(listen_fd)
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::Mutex;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info};
+
+use super::{lock_ignoring_poison, Transport, TransportResult};
+use crate::{constants::MAX_REQUEST_SIZE, TransportError};
+
+/// Implementation of the transport layer that treats stdin/stdout as a single connection.
+///
+/// This allows a server to be run by inetd, systemd (with `Accept=yes`) or `socat`, which accept
+/// the connection and pass it to the process as stdin/stdout. Once that connection has been
+/// served the transport is closed, and the server stops listening.
+///
+/// Logs must not be written to stdout, as they would be sent to the client. Make sure the
+/// [tracing] subscriber writes to stderr instead, e.g.
+/// `tracing_subscriber::fmt().with_writer(std::io::stderr)`.
+pub struct StdioTransport {
+    conn: Mutex<Option<StdioConnection>>,
+}
+
+/// The single connection accepted by [`StdioTransport`]
+pub struct StdioConnection {
+    reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Sync + Unpin>,
+}
+
+impl Default for StdioTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StdioTransport {
+    pub fn new() -> Self {
+        Self::from_io(tokio::io::stdin(), tokio::io::stdout())
+    }
+
+    /// Uses `reader` and `writer` in place of stdin and stdout
+    pub fn from_io(
+        reader: impl AsyncRead + Send + Sync + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Sync + Unpin + 'static,
+    ) -> Self {
+        let conn = StdioConnection {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        };
+        Self {
+            conn: Mutex::new(Some(conn)),
+        }
+    }
+}
+
+impl Transport for StdioTransport {
+    type Addr = ();
+
+    type Connection = StdioConnection;
+
+    async fn bind(&mut self, _local_addr: ()) -> TransportResult<()> {
+        info!("Serving a single connection over stdio");
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        // Only the first call gets the connection, there will never be another one
+        let conn = lock_ignoring_poison(&self.conn)
+            .take()
+            .ok_or(TransportError::Closed)?;
+
+        debug!("Accepted stdio connection");
+        Ok(conn)
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        let mut buf = Vec::with_capacity(MAX_REQUEST_SIZE);
+        let len = conn.reader.read_buf(&mut buf).await?;
+
+        debug!(len, "Successfully read from stdin");
+
+        Ok(buf)
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        debug!(len = response.len(), "Writing to stdout");
+        conn.writer.write_all(response).await?;
+        conn.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn shutdown_conn(&self, mut conn: Self::Connection) -> TransportResult<()> {
+        conn.writer.shutdown().await?;
+        Ok(())
+    }
+
+    fn kind(&self, _conn: &Self::Connection) -> &'static str {
+        "stdio"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::{
+        http::{HttpRequest, HttpResponse},
+        protocol::HttpProtocol,
+        YarsServer,
+    };
+
+    #[tokio::test]
+    async fn accepts_exactly_one_connection() {
+        let (_stdin, reader) = duplex(64);
+        let (writer, _stdout) = duplex(64);
+        let transport = StdioTransport::from_io(reader, writer);
+
+        assert!(transport.accept().await.is_ok());
+        assert!(matches!(
+            transport.accept().await,
+            Err(TransportError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_stops_after_serving_connection() {
+        let (mut stdin, reader) = duplex(1024);
+        let (writer, mut stdout) = duplex(1024);
+
+        stdin.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        // Returns once the only connection has been served
+        YarsServer::new(StdioTransport::from_io(reader, writer), HttpProtocol)
+            .get("/", async |_req: HttpRequest| -> crate::Result<_> {
+                Ok(HttpResponse::Ok().text("hi"))
+            })
+            .listen(())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stdout.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+    }
+}
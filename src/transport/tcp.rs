@@ -1,16 +1,46 @@
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+
+pub use socket2::TcpKeepalive;
+use socket2::{Domain, SockRef, Socket, Type};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    net::{TcpListener, TcpStream},
 };
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use super::{Transport, TransportResult};
+#[cfg(unix)]
+use super::listen_fds;
+use super::{socket_addrs::bind_socket, SocketAddrs, Transport, TransportResult};
 use crate::{constants::MAX_REQUEST_SIZE, TransportError};
 
+/// Default maximum number of pending connections, the same as [`TcpListener::bind`] uses
+const BACKLOG: u32 = 1024;
+
 /// Implementation of the transport layer for TCP connections
+///
+/// Socket options are configured with the builder methods. Options for accepted connections
+/// (e.g. [`nodelay`][TcpTransport::nodelay] and [`keepalive`][TcpTransport::keepalive]) are set
+/// before they are read from, and checked when binding, so that invalid options fail there rather
+/// than for every connection. Options for the listener (e.g.
+/// [`backlog`][TcpTransport::backlog]) are set when binding, so have no effect on sockets passed
+/// by systemd or a previous process.
 #[derive(Default)]
 pub struct TcpTransport {
     listener: Option<TcpListener>,
+    nodelay: Option<bool>,
+    keepalive: Option<TcpKeepalive>,
+    backlog: Option<u32>,
+    reuse_address: Option<bool>,
+    #[cfg(unix)]
+    reuse_port: bool,
+    only_v6: Option<bool>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fastopen: Option<u32>,
 }
 
 impl TcpTransport {
@@ -18,6 +48,143 @@ impl TcpTransport {
         Self::default()
     }
 
+    /// The local address that the transport is bound to
+    pub fn local_addr(&self) -> TransportResult<SocketAddr> {
+        Ok(self.listener()?.local_addr()?)
+    }
+
+    /// Set `TCP_NODELAY` on accepted connections, disabling Nagle's algorithm so that small
+    /// responses are sent straight away
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enable `SO_KEEPALIVE` on accepted connections, with the given idle time, probe interval
+    /// and probe count, so that idle connections aren't dropped by NATs and dead peers are
+    /// noticed.
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use yars::transport::{TcpKeepalive, TcpTransport};
+    ///
+    /// let transport = TcpTransport::new().keepalive(
+    ///     TcpKeepalive::new()
+    ///         .with_time(Duration::from_secs(60))
+    ///         .with_interval(Duration::from_secs(10))
+    ///         .with_retries(5),
+    /// );
+    /// ```
+    pub fn keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Maximum number of pending connections waiting to be accepted, 1024 by default
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on the listener, so that the address can be bound again straight away
+    /// after the server stops. Enabled by default on Unix, like [`TcpListener::bind`].
+    pub fn reuse_address(mut self, reuse_address: bool) -> Self {
+        self.reuse_address = Some(reuse_address);
+        self
+    }
+
+    /// Bind with `SO_REUSEPORT`, so that multiple processes can listen on the same address and
+    /// the kernel balances connections between them, see
+    /// [`YarsServer::workers`][crate::YarsServer::workers]
+    #[cfg(unix)]
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Set `IPV6_V6ONLY` when binding to an IPv6 address, so that the listener doesn't also accept
+    /// IPv4 connections. The default depends on the OS.
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Size of the receive buffer (`SO_RCVBUF`) of the listener and accepted connections
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Size of the send buffer (`SO_SNDBUF`) of the listener and accepted connections
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Enable `TCP_FASTOPEN` on the listener, allowing up to `queue_len` pending fast open
+    /// requests, so that returning clients can send their request in the SYN
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn fastopen(mut self, queue_len: u32) -> Self {
+        self.fastopen = Some(queue_len);
+        self
+    }
+
+    async fn bind_new(&self, addrs: &[SocketAddr]) -> io::Result<TcpListener> {
+        let socket = bind_socket(addrs, Type::STREAM, |socket, addr| {
+            self.configure_listener(socket, addr)
+        })?;
+        let backlog = self.backlog.unwrap_or(BACKLOG);
+        socket.listen(backlog.try_into().unwrap_or(i32::MAX))?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Sets the listener's socket options, before it is bound to `addr`
+    fn configure_listener(&self, socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+        socket.set_reuse_address(self.reuse_address.unwrap_or(cfg!(unix)))?;
+        #[cfg(unix)]
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        if let Some(only_v6) = self.only_v6.filter(|_| addr.is_ipv6()) {
+            socket.set_only_v6(only_v6)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(queue_len) = self.fastopen {
+            set_fastopen(socket, queue_len)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the socket options of an accepted connection
+    fn configure_stream(&self, socket: SockRef) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_tcp_keepalive(keepalive)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the options for accepted connections on an unconnected socket, so that options the OS
+    /// rejects fail when binding
+    fn check_stream_options(&self, addr: SocketAddr) -> io::Result<()> {
+        let probe = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        self.configure_stream(SockRef::from(&probe))
+    }
+
     fn listener(&self) -> TransportResult<&TcpListener> {
         // Error should never happen because this should only be used internally
         self.listener.as_ref().ok_or(TransportError::Tcp(
@@ -26,23 +193,85 @@ impl TcpTransport {
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_fastopen(socket: &Socket, queue_len: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let queue_len = libc::c_int::try_from(queue_len).unwrap_or(libc::c_int::MAX);
+    // SAFETY: `queue_len` is a valid `c_int`, and its size is passed
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            (&queue_len as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Listens on an inherited socket
+#[cfg(unix)]
+fn adopt(fd: OwnedFd) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
 impl Transport for TcpTransport {
+    type Addr = SocketAddrs;
+
     type Connection = TcpStream;
 
-    async fn bind(&mut self, local_addr: impl ToSocketAddrs) -> TransportResult<()> {
-        let listener = TcpListener::bind(local_addr).await?;
-        info!(
-            "Listening for TCP connections on {}",
-            listener.local_addr()?
-        );
+    async fn bind(&mut self, local_addr: SocketAddrs) -> TransportResult<()> {
+        let listener = match local_addr {
+            #[cfg(unix)]
+            SocketAddrs::Systemd(listen_fd) => adopt(listen_fd.take()?)?,
+            local_addr => {
+                let addrs = local_addr.resolve().await?;
+                // Already bound by systemd, or by the previous process on a hot restart
+                #[cfg(unix)]
+                if let Some(fd) = listen_fds::take_socket_bound_to(Type::STREAM, &addrs) {
+                    adopt(fd)?
+                } else {
+                    self.bind_new(&addrs).await?
+                }
+                #[cfg(not(unix))]
+                self.bind_new(&addrs).await?
+            }
+        };
+        let addr = listener.local_addr()?;
+        self.check_stream_options(addr)?;
+        info!("Listening for TCP connections on {addr}");
         self.listener = Some(listener);
         Ok(())
     }
 
     async fn accept(&self) -> TransportResult<Self::Connection> {
-        let (stream, addr) = self.listener()?.accept().await?;
-        debug!(%addr, "Accepted TCP connection");
-        Ok(stream)
+        loop {
+            let (stream, addr) = self.listener()?.accept().await?;
+            debug!(%addr, "Accepted TCP connection");
+            match self.configure_stream(SockRef::from(&stream)) {
+                Ok(()) => return Ok(stream),
+                // The peer has already gone away. Only this connection is affected, so it
+                // mustn't stop the server from accepting others
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::NotConnected
+                            | io::ErrorKind::InvalidInput
+                    ) =>
+                {
+                    warn!(%addr, %err, "Failed to set socket options, dropping connection")
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
     }
 
     async fn read(&self, stream: &mut Self::Connection) -> TransportResult<Vec<u8>> {
@@ -71,4 +300,88 @@ impl Transport for TcpTransport {
         stream.shutdown().await?;
         Ok(())
     }
+
+    fn peer_addr(&self, stream: &Self::Connection) -> Option<SocketAddr> {
+        stream.peer_addr().ok()
+    }
+
+    fn conn_local_addr(&self, stream: &Self::Connection) -> Option<SocketAddr> {
+        stream.local_addr().ok()
+    }
+
+    fn kind(&self, _stream: &Self::Connection) -> &'static str {
+        "tcp"
+    }
+
+    #[cfg(unix)]
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        self.listener.iter().map(AsFd::as_fd).collect()
+    }
+
+    #[cfg(unix)]
+    fn set_reuse_port(&mut self, reuse_port: bool) -> TransportResult<()> {
+        self.reuse_port = reuse_port;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sets_socket_options() {
+        let mut transport = TcpTransport::new()
+            .nodelay(true)
+            .keepalive(TcpKeepalive::new().with_time(Duration::from_secs(30)))
+            .reuse_address(false)
+            .send_buffer_size(64 * 1024);
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let listener = SockRef::from(transport.listener().unwrap());
+        assert!(!listener.reuse_address().unwrap());
+        // The kernel may round the size up
+        assert!(listener.send_buffer_size().unwrap() >= 64 * 1024);
+
+        let _client = TcpStream::connect(transport.local_addr().unwrap())
+            .await
+            .unwrap();
+        let stream = transport.accept().await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn rejects_invalid_options_when_binding() {
+        // Linux rejects a keepalive idle time of 0 with EINVAL
+        let mut transport =
+            TcpTransport::new().keepalive(TcpKeepalive::new().with_time(Duration::ZERO));
+        assert!(transport.bind("127.0.0.1:0".into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_connection_addresses() {
+        let mut transport = TcpTransport::new();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+
+        let client = TcpStream::connect(transport.local_addr().unwrap())
+            .await
+            .unwrap();
+        let stream = transport.accept().await.unwrap();
+        assert_eq!(
+            transport.peer_addr(&stream),
+            Some(client.local_addr().unwrap())
+        );
+        assert_eq!(
+            transport.conn_local_addr(&stream),
+            Some(client.peer_addr().unwrap())
+        );
+        assert_eq!(transport.kind(&stream), "tcp");
+    }
 }
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::trace;
+
+use super::{delegate_to_inner, lock_ignoring_poison, Transport, TransportResult};
+
+/// Largest write made at once when writes are throttled, so that a large response is sent at a
+/// steady rate rather than in one burst after a long wait
+const WRITE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Wraps any transport, limiting the bandwidth of each connection, and optionally of all
+/// connections from the same IP address, e.g. to cap downloads from bulk endpoints.
+///
+/// Limits are in bytes per second, and allow bursts of up to a second's worth of bytes. Reads
+/// wait after reading until the bytes are within the limit, so the next read is delayed. Writes
+/// are split into chunks that each wait until they're within the limit.
+///
+/// Bytes are counted per connection, see [`ThrottledConnection::bytes`], and per IP address once
+/// [`per_peer`][Self::per_peer] accounting or limits are enabled, see
+/// [`peer_bytes`][Self::peer_bytes].
+///
+/// ```rust
+/// use yars::transport::{TcpTransport, ThrottledTransport};
+///
+/// // 1 MiB/s to each client, however many connections it opens
+/// let transport = ThrottledTransport::new(TcpTransport::new()).peer_write_limit(1024 * 1024);
+/// ```
+pub struct ThrottledTransport<T> {
+    inner: T,
+    read_limit: Option<u64>,
+    write_limit: Option<u64>,
+    peer_read_limit: Option<u64>,
+    peer_write_limit: Option<u64>,
+    per_peer: bool,
+    /// Usage of every IP address that has connected. Only used if `per_peer` is set.
+    peers: Mutex<HashMap<IpAddr, Arc<Usage>>>,
+}
+
+/// Connection accepted by [`ThrottledTransport`]
+pub struct ThrottledConnection<C> {
+    inner: C,
+    usage: Usage,
+    /// Shared with the peer's other connections. Found on first use, as some transports only
+    /// know the peer address after reading, e.g. [`ProxyProtocolTransport`][super::ProxyProtocolTransport].
+    peer: Option<Arc<Usage>>,
+    /// Bytes that have been read and charged, held back until the instant they are within the
+    /// limits. Kept here so that cancelling a read doesn't lose them.
+    held: Option<(Vec<u8>, Instant)>,
+}
+
+/// Bytes read from and written to a connection or peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ByteCount {
+    pub read: u64,
+    pub written: u64,
+}
+
+/// Bytes counted and rate limits for a connection or peer
+struct Usage {
+    read: AtomicU64,
+    written: AtomicU64,
+    read_limit: Option<RateLimiter>,
+    write_limit: Option<RateLimiter>,
+}
+
+impl Usage {
+    fn new(read_limit: Option<u64>, write_limit: Option<u64>) -> Self {
+        Self {
+            read: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            read_limit: read_limit.map(RateLimiter::new),
+            write_limit: write_limit.map(RateLimiter::new),
+        }
+    }
+
+    fn bytes(&self) -> ByteCount {
+        ByteCount {
+            read: self.read.load(Relaxed),
+            written: self.written.load(Relaxed),
+        }
+    }
+}
+
+/// Token bucket holding up to a second's worth of bytes. Bytes can be taken even if there aren't
+/// enough tokens, leaving the bucket in debt, so that large reads and writes aren't starved by
+/// smaller ones.
+struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    /// How long to wait until `len` bytes are within the limit
+    fn take(&self, len: usize) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let mut bucket = lock_ignoring_poison(&self.bucket);
+        let (tokens, refilled) = &mut *bucket;
+
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * rate).min(rate);
+        *refilled = now;
+        *tokens -= len as f64;
+
+        match *tokens < 0.0 {
+            true => Duration::from_secs_f64(-*tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Waits until `len` bytes are within all of `limits`
+async fn throttle(limits: [Option<&RateLimiter>; 2], len: usize) {
+    let delay = charge(limits, len);
+    if !delay.is_zero() {
+        sleep(delay).await;
+    }
+}
+
+/// Charges `len` bytes to every limit, returning how long to wait until they are within all of
+/// them
+fn charge(limits: [Option<&RateLimiter>; 2], len: usize) -> Duration {
+    // Every limit is charged before waiting, as the bytes will be sent after the longest wait
+    let delay = limits
+        .into_iter()
+        .flatten()
+        .map(|limit| limit.take(len))
+        .max()
+        .unwrap_or_default();
+    if !delay.is_zero() {
+        trace!(len, ?delay, "Throttling connection");
+    }
+    delay
+}
+
+impl<C> ThrottledConnection<C> {
+    /// The wrapped connection
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Bytes read from and written to the connection so far
+    pub fn bytes(&self) -> ByteCount {
+        self.usage.bytes()
+    }
+}
+
+impl<T: Transport> ThrottledTransport<T> {
+    /// No limits are set by default
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            read_limit: None,
+            write_limit: None,
+            peer_read_limit: None,
+            peer_write_limit: None,
+            per_peer: false,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Limit the bytes per second read from each connection
+    pub fn read_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.read_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limit the bytes per second written to each connection
+    pub fn write_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.write_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limit the bytes per second read from all connections from the same IP address. Enables
+    /// [`per_peer`][Self::per_peer] accounting.
+    pub fn peer_read_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.peer_read_limit = Some(bytes_per_sec);
+        self.per_peer = true;
+        self
+    }
+
+    /// Limit the bytes per second written to all connections from the same IP address. Enables
+    /// [`per_peer`][Self::per_peer] accounting.
+    pub fn peer_write_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.peer_write_limit = Some(bytes_per_sec);
+        self.per_peer = true;
+        self
+    }
+
+    /// Count bytes per IP address, see [`peer_bytes`][Self::peer_bytes]. Off by default, as an
+    /// entry is kept for every IP address that ever connects.
+    pub fn per_peer(mut self, per_peer: bool) -> Self {
+        self.per_peer = per_peer;
+        self
+    }
+
+    /// Bytes read from and written to all connections from `ip`, if it has connected and
+    /// [`per_peer`][Self::per_peer] accounting is enabled
+    pub fn peer_bytes(&self, ip: IpAddr) -> Option<ByteCount> {
+        self.peers().get(&ip).map(|usage| usage.bytes())
+    }
+
+    /// The wrapped transport
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, Arc<Usage>>> {
+        lock_ignoring_poison(&self.peers)
+    }
+
+    /// Finds the usage of the connection's peer, if it's known and needed
+    fn peer_usage(&self, conn: &mut ThrottledConnection<T::Connection>) -> Option<Arc<Usage>> {
+        if !self.per_peer {
+            return None;
+        }
+        if conn.peer.is_none() {
+            let ip = self.inner.peer_addr(&conn.inner)?.ip();
+            let usage = self
+                .peers()
+                .entry(ip)
+                .or_insert_with(|| {
+                    Arc::new(Usage::new(self.peer_read_limit, self.peer_write_limit))
+                })
+                .clone();
+            conn.peer = Some(usage);
+        }
+        conn.peer.clone()
+    }
+}
+
+impl<T: Transport> Transport for ThrottledTransport<T> {
+    type Addr = T::Addr;
+
+    type Connection = ThrottledConnection<T::Connection>;
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let inner = self.inner.accept().await?;
+        Ok(ThrottledConnection {
+            inner,
+            usage: Usage::new(self.read_limit, self.write_limit),
+            peer: None,
+            held: None,
+        })
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        if conn.held.is_none() {
+            let data = self.inner.read(&mut conn.inner).await?;
+            let peer = self.peer_usage(conn);
+
+            conn.usage.read.fetch_add(data.len() as u64, Relaxed);
+            if let Some(peer) = &peer {
+                peer.read.fetch_add(data.len() as u64, Relaxed);
+            }
+            let peer_limit = peer.as_ref().and_then(|peer| peer.read_limit.as_ref());
+            let delay = charge([conn.usage.read_limit.as_ref(), peer_limit], data.len());
+            if delay.is_zero() {
+                return Ok(data);
+            }
+            conn.held = Some((data, Instant::now() + delay));
+        }
+
+        if let Some((_, until)) = &conn.held {
+            sleep_until(*until).await;
+        }
+        Ok(conn.held.take().map(|(data, _)| data).unwrap_or_default())
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        let peer = self.peer_usage(conn);
+        let peer_limit = peer.as_ref().and_then(|peer| peer.write_limit.as_ref());
+        let throttled = conn.usage.write_limit.is_some() || peer_limit.is_some();
+
+        let chunk_size = match throttled {
+            true => WRITE_CHUNK_SIZE,
+            false => response.len(),
+        };
+        for chunk in response.chunks(chunk_size.max(1)) {
+            throttle([conn.usage.write_limit.as_ref(), peer_limit], chunk.len()).await;
+            self.inner.write(&mut conn.inner, chunk).await?;
+
+            conn.usage.written.fetch_add(chunk.len() as u64, Relaxed);
+            if let Some(peer) = &peer {
+                peer.written.fetch_add(chunk.len() as u64, Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        self.inner.shutdown_conn(conn.inner).await
+    }
+
+    delegate_to_inner!(
+        bind,
+        peer_addr,
+        conn_local_addr,
+        kind,
+        extensions,
+        listeners,
+        set_reuse_port,
+        shutdown
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::transport::{MemoryTransport, TcpTransport};
+
+    #[tokio::test(start_paused = true)]
+    async fn throttles_writes() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = ThrottledTransport::new(inner).write_limit(10_000);
+        transport.bind(()).await.unwrap();
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+        let start = Instant::now();
+        // A second's worth of bytes can be written straight away, the rest takes 2 seconds
+        transport.write(&mut conn, &[0; 30_000]).await.unwrap();
+        assert_eq!(start.elapsed().as_secs(), 2);
+        assert_eq!(
+            conn.bytes(),
+            ByteCount {
+                read: 0,
+                written: 30_000
+            }
+        );
+
+        transport.shutdown_conn(conn).await.unwrap();
+        let mut response = Vec::new();
+        client_stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response.len(), 30_000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttles_reads() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = ThrottledTransport::new(inner).read_limit(100);
+        transport.bind(()).await.unwrap();
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            client_stream.write_all(&[0; 100]).await.unwrap();
+            assert_eq!(transport.read(&mut conn).await.unwrap().len(), 100);
+        }
+        assert_eq!(start.elapsed().as_secs(), 2);
+        assert_eq!(conn.bytes().read, 300);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_throttled_reads_when_cancelled() {
+        let (inner, client) = MemoryTransport::new();
+        let mut transport = ThrottledTransport::new(inner).read_limit(100);
+        transport.bind(()).await.unwrap();
+
+        let mut client_stream = client.connect().unwrap();
+        let mut conn = transport.accept().await.unwrap();
+        client_stream.write_all(&[0; 200]).await.unwrap();
+        // Cancelled while waiting for the second 100 bytes to be within the limit
+        assert!(
+            timeout(Duration::from_millis(500), transport.read(&mut conn))
+                .await
+                .is_err()
+        );
+
+        let start = Instant::now();
+        assert_eq!(transport.read(&mut conn).await.unwrap().len(), 200);
+        // Only the rest of the wait
+        assert_eq!(start.elapsed().as_millis(), 500);
+        assert_eq!(conn.bytes().read, 200);
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_per_peer() {
+        let mut transport = ThrottledTransport::new(TcpTransport::new()).per_peer(true);
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let addr = transport.inner().local_addr().unwrap();
+
+        for request in [&b"first"[..], b"second"] {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(request).await.unwrap();
+            let mut conn = transport.accept().await.unwrap();
+            transport.read(&mut conn).await.unwrap();
+            transport.write(&mut conn, b"response").await.unwrap();
+            transport.shutdown_conn(conn).await.unwrap();
+        }
+
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            transport.peer_bytes(ip),
+            Some(ByteCount {
+                read: 11,
+                written: 16
+            })
+        );
+        assert_eq!(transport.peer_bytes("127.0.0.2".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,744 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::BorrowedFd;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    Accept, TlsAcceptor,
+};
+use tracing::{debug, info};
+
+mod peer_certificate;
+
+use super::{Transport, TransportResult};
+use crate::{constants::MAX_REQUEST_SIZE, Extensions, TransportError};
+
+pub use peer_certificate::{PeerCertificate, SubjectAltName};
+
+/// TLS termination on top of any stream-based transport, e.g. [`TcpTransport`][super::TcpTransport]
+///
+/// The certificate presented to each client is selected based on the server name it requested
+/// (SNI), see [`TlsConfig`].
+///
+/// The TLS handshake happens on the first read from a connection, so a slow client doesn't hold
+/// up accepting other connections.
+///
+/// Requires the `tls` feature.
+pub struct TlsTransport<T> {
+    inner: T,
+    acceptor: TlsAcceptor,
+}
+
+/// Certificates used by [`TlsTransport`], and optionally the CAs used to verify client
+/// certificates (mutual TLS)
+///
+/// ```rust,no_run
+/// # fn main() -> yars::transport::TransportResult<()> {
+/// use yars::transport::{TcpTransport, TlsConfig, TlsTransport};
+///
+/// let config = TlsConfig::new()
+///     // used when the client doesn't send a server name, or it doesn't match any below
+///     .cert_files("default.crt", "default.key")?
+///     .sni_cert_files("example.com", "example.crt", "example.key")?
+///     .sni_cert_files("*.example.com", "wildcard.crt", "wildcard.key")?;
+/// let transport = TlsTransport::new(TcpTransport::new(), config)?;
+///
+/// // Only accept clients with a certificate signed by our CA
+/// let config = TlsConfig::new()
+///     .cert_files("server.crt", "server.key")?
+///     .client_ca_file("ca.crt")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TlsConfig {
+    provider: Arc<CryptoProvider>,
+    resolver: SniResolver,
+    /// CAs that client certificates must be signed by, if client authentication is enabled
+    client_roots: Option<RootCertStore>,
+    client_auth_optional: bool,
+}
+
+/// A self-signed certificate, for local development and tests
+pub struct SelfSignedCert {
+    /// PEM encoded certificate
+    pub cert_pem: String,
+    /// PEM encoded private key
+    pub key_pem: String,
+    cert_der: CertificateDer<'static>,
+}
+
+/// TLS session information, attached to every request as an extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Server name the client requested using SNI, if any
+    pub server_name: Option<String>,
+}
+
+/// Connection accepted by [`TlsTransport`]
+pub struct TlsConnection<C> {
+    /// The underlying connection, until the TLS handshake starts
+    pending: Option<C>,
+    /// Kept here until it is done, so that cancelling a read or write doesn't lose its progress
+    handshake: Option<Accept<C>>,
+    stream: Option<TlsStream<C>>,
+}
+
+/// Picks a certificate based on the server name the client requested
+#[derive(Debug, Default)]
+struct SniResolver {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(server_name) = server_name.map(str::to_ascii_lowercase) else {
+            return self.default.clone();
+        };
+
+        let wildcard = server_name
+            .split_once('.')
+            .map(|(_label, parent)| format!("*.{parent}"));
+
+        self.by_name
+            .get(&server_name)
+            .or_else(|| wildcard.and_then(|wildcard| self.by_name.get(&wildcard)))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let cert = self.lookup(client_hello.server_name());
+        if cert.is_none() {
+            debug!(
+                server_name = client_hello.server_name(),
+                "No TLS certificate for server name"
+            );
+        }
+        cert
+    }
+}
+
+fn tls_error(err: impl std::fmt::Display) -> TransportError {
+    TransportError::Tls(err.to_string())
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self {
+            provider: Arc::new(ring::default_provider()),
+            resolver: SniResolver::default(),
+            client_roots: None,
+            client_auth_optional: false,
+        }
+    }
+
+    /// A config with a freshly generated self-signed certificate for `subject_alt_names`, e.g.
+    /// `["localhost"]`, as the default certificate.
+    ///
+    /// Clients won't trust the certificate, use [`SelfSignedCert::generate`] instead if the
+    /// certificate needs to be given to clients.
+    pub fn self_signed(subject_alt_names: impl Into<Vec<String>>) -> TransportResult<Self> {
+        let cert = SelfSignedCert::generate(subject_alt_names)?;
+        Self::new().cert_pem(cert.cert_pem.as_bytes(), cert.key_pem.as_bytes())
+    }
+
+    /// Sets the default certificate, used when the client doesn't request a server name or no
+    /// certificate matches the server name
+    pub fn cert_pem(mut self, cert_chain: &[u8], key: &[u8]) -> TransportResult<Self> {
+        self.resolver.default = Some(self.certified_key(cert_chain, key)?);
+        Ok(self)
+    }
+
+    /// Adds a certificate to present to clients that request `server_name` using SNI.
+    ///
+    /// `server_name` can be a wildcard, e.g. `*.example.com`, matching a single label.
+    pub fn sni_cert_pem(
+        mut self,
+        server_name: &str,
+        cert_chain: &[u8],
+        key: &[u8],
+    ) -> TransportResult<Self> {
+        let cert = self.certified_key(cert_chain, key)?;
+        self.resolver
+            .by_name
+            .insert(server_name.to_ascii_lowercase(), cert);
+        Ok(self)
+    }
+
+    /// Like [`cert_pem`][Self::cert_pem], reading the PEM files at the given paths
+    pub fn cert_files(
+        self,
+        cert_chain: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> TransportResult<Self> {
+        let cert_chain = std::fs::read(cert_chain)?;
+        let key = std::fs::read(key)?;
+        self.cert_pem(&cert_chain, &key)
+    }
+
+    /// Like [`sni_cert_pem`][Self::sni_cert_pem], reading the PEM files at the given paths
+    pub fn sni_cert_files(
+        self,
+        server_name: &str,
+        cert_chain: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> TransportResult<Self> {
+        let cert_chain = std::fs::read(cert_chain)?;
+        let key = std::fs::read(key)?;
+        self.sni_cert_pem(server_name, &cert_chain, &key)
+    }
+
+    /// Enables client authentication: clients must present a certificate signed by one of the
+    /// CAs in the PEM encoded `ca_bundle`. Can be called multiple times to add more CAs.
+    ///
+    /// The verified certificate is attached to every request as a [`PeerCertificate`] extension.
+    pub fn client_ca_pem(mut self, ca_bundle: &[u8]) -> TransportResult<Self> {
+        let roots = self.client_roots.get_or_insert_with(RootCertStore::empty);
+        for cert in CertificateDer::pem_slice_iter(ca_bundle) {
+            roots.add(cert.map_err(tls_error)?).map_err(tls_error)?;
+        }
+
+        if roots.is_empty() {
+            return Err(tls_error("No CA certificates found in PEM"));
+        }
+        Ok(self)
+    }
+
+    /// Like [`client_ca_pem`][Self::client_ca_pem], reading the PEM file at the given path
+    pub fn client_ca_file(self, ca_bundle: impl AsRef<Path>) -> TransportResult<Self> {
+        let ca_bundle = std::fs::read(ca_bundle)?;
+        self.client_ca_pem(&ca_bundle)
+    }
+
+    /// Also accept clients that don't present a certificate, when client authentication is
+    /// enabled. Certificates that are presented must still be valid.
+    pub fn client_auth_optional(mut self) -> Self {
+        self.client_auth_optional = true;
+        self
+    }
+
+    fn certified_key(&self, cert_chain: &[u8], key: &[u8]) -> TransportResult<Arc<CertifiedKey>> {
+        let cert_chain = CertificateDer::pem_slice_iter(cert_chain)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(tls_error)?;
+        if cert_chain.is_empty() {
+            return Err(tls_error("No certificates found in PEM"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(tls_error)?;
+
+        let cert = CertifiedKey::from_der(cert_chain, key, &self.provider).map_err(tls_error)?;
+        Ok(Arc::new(cert))
+    }
+
+    pub(super) fn into_server_config(self) -> TransportResult<ServerConfig> {
+        let builder = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+
+        let builder = match self.client_roots {
+            Some(roots) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), self.provider);
+                let verifier = match self.client_auth_optional {
+                    true => verifier.allow_unauthenticated(),
+                    false => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(tls_error)?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(builder.with_cert_resolver(Arc::new(self.resolver)))
+    }
+}
+
+impl SelfSignedCert {
+    /// Generates a self-signed certificate for `subject_alt_names`, e.g. `["localhost"]`
+    pub fn generate(subject_alt_names: impl Into<Vec<String>>) -> TransportResult<Self> {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(subject_alt_names).map_err(tls_error)?;
+
+        Ok(Self {
+            cert_pem: cert.pem(),
+            key_pem: key_pair.serialize_pem(),
+            cert_der: cert.der().clone(),
+        })
+    }
+
+    /// DER encoded certificate, e.g. for adding to a client's root certificates
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert_der
+    }
+}
+
+impl<T> TlsTransport<T>
+where
+    T: Transport,
+    T::Connection: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(inner: T, config: TlsConfig) -> TransportResult<Self> {
+        let acceptor = TlsAcceptor::from(Arc::new(config.into_server_config()?));
+        Ok(Self { inner, acceptor })
+    }
+
+    /// The wrapped transport
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<C> TlsConnection<C>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs the TLS handshake if it hasn't been done yet
+    async fn stream(&mut self, acceptor: &TlsAcceptor) -> TransportResult<&mut TlsStream<C>> {
+        if let Some(conn) = self.pending.take() {
+            self.handshake = Some(acceptor.accept(conn));
+        }
+        if let Some(handshake) = &mut self.handshake {
+            let result = handshake.await;
+            self.handshake = None;
+            let stream = result?;
+            let (_, session) = stream.get_ref();
+            debug!(
+                server_name = session.server_name(),
+                version = ?session.protocol_version(),
+                client_cert = session.peer_certificates().is_some(),
+                "TLS handshake complete"
+            );
+            self.stream = Some(stream);
+        }
+
+        self.stream
+            .as_mut()
+            .ok_or(tls_error("TLS handshake previously failed"))
+    }
+
+    fn inner(&self) -> Option<&C> {
+        self.pending
+            .as_ref()
+            .or(self.handshake.as_ref().and_then(Accept::get_ref))
+            .or(self.stream.as_ref().map(|stream| stream.get_ref().0))
+    }
+}
+
+impl<T> Transport for TlsTransport<T>
+where
+    T: Transport,
+    T::Connection: AsyncRead + AsyncWrite + Unpin,
+{
+    type Addr = T::Addr;
+
+    type Connection = TlsConnection<T::Connection>;
+
+    async fn bind(&mut self, local_addr: Self::Addr) -> TransportResult<()> {
+        self.inner.bind(local_addr).await?;
+        info!("TLS enabled");
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let conn = self.inner.accept().await?;
+        Ok(TlsConnection {
+            pending: Some(conn),
+            handshake: None,
+            stream: None,
+        })
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        let stream = conn.stream(&self.acceptor).await?;
+
+        let mut buf = Vec::with_capacity(MAX_REQUEST_SIZE);
+        let len = stream.read_buf(&mut buf).await?;
+
+        debug!(len, "Successfully read from TLS connection");
+
+        Ok(buf)
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        let stream = conn.stream(&self.acceptor).await?;
+
+        debug!(len = response.len(), "Writing to TLS connection");
+        stream.write_all(response).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        let inner = match (conn.pending, conn.stream) {
+            (Some(inner), _) => inner,
+            (None, Some(mut stream)) => {
+                // Let the client know we're done, the underlying connection is shut down below
+                stream.get_mut().1.send_close_notify();
+                stream.flush().await?;
+                stream.into_inner().0
+            }
+            // Handshake failed, in which case the underlying connection has already been
+            // dropped, or is unfinished and is dropped with it
+            (None, None) => return Ok(()),
+        };
+        self.inner.shutdown_conn(inner).await
+    }
+
+    fn peer_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        conn.inner().and_then(|inner| self.inner.peer_addr(inner))
+    }
+
+    fn conn_local_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        conn.inner()
+            .and_then(|inner| self.inner.conn_local_addr(inner))
+    }
+
+    fn kind(&self, _conn: &Self::Connection) -> &'static str {
+        "tls"
+    }
+
+    fn extensions(&self, conn: &Self::Connection) -> Extensions {
+        let mut extensions = conn
+            .inner()
+            .map(|inner| self.inner.extensions(inner))
+            .unwrap_or_default();
+
+        if let Some(stream) = &conn.stream {
+            let (_, session) = stream.get_ref();
+            extensions.insert(TlsInfo {
+                server_name: session.server_name().map(str::to_string),
+            });
+
+            // The end-entity certificate comes first
+            if let Some(cert) = session
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| PeerCertificate::from_der(cert))
+            {
+                extensions.insert(cert);
+            }
+        }
+
+        extensions
+    }
+
+    #[cfg(unix)]
+    fn set_reuse_port(&mut self, reuse_port: bool) -> TransportResult<()> {
+        self.inner.set_reuse_port(reuse_port)
+    }
+
+    #[cfg(unix)]
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        self.inner.listeners()
+    }
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+    use tokio_rustls::{
+        client::TlsStream as ClientTlsStream,
+        rustls::{pki_types::ServerName, ClientConfig},
+        TlsConnector,
+    };
+
+    use super::*;
+    use crate::transport::TcpTransport;
+
+    type ClientCert = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+    async fn try_connect(
+        addr: std::net::SocketAddr,
+        server_name: &'static str,
+        trusted: &[&SelfSignedCert],
+        client_cert: Option<ClientCert>,
+    ) -> std::io::Result<ClientTlsStream<TcpStream>> {
+        let tcp = TcpStream::connect(addr).await?;
+        handshake(tcp, server_name, trusted, client_cert).await
+    }
+
+    async fn handshake(
+        tcp: TcpStream,
+        server_name: &'static str,
+        trusted: &[&SelfSignedCert],
+        client_cert: Option<ClientCert>,
+    ) -> std::io::Result<ClientTlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        for cert in trusted {
+            roots.add(cert.cert_der.clone()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some((cert_chain, key)) => config.with_client_auth_cert(cert_chain, key).unwrap(),
+            None => config.with_no_client_auth(),
+        };
+
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(server_name).unwrap(), tcp)
+            .await
+    }
+
+    async fn connect(
+        addr: std::net::SocketAddr,
+        server_name: &'static str,
+        trusted: &[&SelfSignedCert],
+    ) -> ClientTlsStream<TcpStream> {
+        try_connect(addr, server_name, trusted, None).await.unwrap()
+    }
+
+    /// Generates a CA, and a client certificate signed by it
+    fn client_ca_and_cert() -> (String, ClientCert) {
+        use rcgen::{
+            BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        };
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params =
+            CertificateParams::new(vec!["client.test".to_string(), "127.0.0.1".to_string()])
+                .unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "client");
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let key = PrivateKeyDer::from_pem_slice(client_key.serialize_pem().as_bytes()).unwrap();
+        (ca.pem(), (vec![client.der().clone()], key))
+    }
+
+    async fn mtls_transport(
+        server: &SelfSignedCert,
+        ca_pem: &str,
+        optional: bool,
+    ) -> TlsTransport<TcpTransport> {
+        let config = TlsConfig::new()
+            .cert_pem(server.cert_pem.as_bytes(), server.key_pem.as_bytes())
+            .unwrap()
+            .client_ca_pem(ca_pem.as_bytes())
+            .unwrap();
+        let config = match optional {
+            true => config.client_auth_optional(),
+            false => config,
+        };
+
+        let mut transport = TlsTransport::new(TcpTransport::new(), config).unwrap();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        transport
+    }
+
+    #[test]
+    fn resolver_matches_exact_then_wildcard_then_default() {
+        let config = TlsConfig::new();
+        let cert = |name: &str| {
+            let cert = SelfSignedCert::generate([name.to_string()]).unwrap();
+            config
+                .certified_key(cert.cert_pem.as_bytes(), cert.key_pem.as_bytes())
+                .unwrap()
+        };
+        let (default, exact, wildcard) = (cert("default"), cert("a.test"), cert("*.test"));
+
+        let resolver = SniResolver {
+            default: Some(default.clone()),
+            by_name: HashMap::from([
+                ("a.test".to_string(), exact.clone()),
+                ("*.test".to_string(), wildcard.clone()),
+            ]),
+        };
+
+        let resolved = |name| resolver.lookup(name).unwrap();
+        assert!(Arc::ptr_eq(&resolved(Some("A.test")), &exact));
+        assert!(Arc::ptr_eq(&resolved(Some("b.test")), &wildcard));
+        assert!(Arc::ptr_eq(&resolved(Some("b.example")), &default));
+        assert!(Arc::ptr_eq(&resolved(None), &default));
+    }
+
+    #[tokio::test]
+    async fn selects_certificate_by_server_name() {
+        let a = SelfSignedCert::generate(["a.test".to_string()]).unwrap();
+        let b = SelfSignedCert::generate(["b.test".to_string()]).unwrap();
+        let config = TlsConfig::new()
+            .cert_pem(a.cert_pem.as_bytes(), a.key_pem.as_bytes())
+            .unwrap()
+            .sni_cert_pem("b.test", b.cert_pem.as_bytes(), b.key_pem.as_bytes())
+            .unwrap();
+
+        let mut transport = TlsTransport::new(TcpTransport::new(), config).unwrap();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let addr = transport.inner().local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            // Only trusts b's certificate, so the handshake fails if a's is presented
+            let mut stream = connect(addr, "b.test", &[&b]).await;
+            stream.write_all(b"ping").await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let mut conn = transport.accept().await.unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+
+        let extensions = transport.extensions(&conn);
+        let tls = extensions.get::<TlsInfo>().unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("b.test"));
+
+        transport.write(&mut conn, b"pong").await.unwrap();
+        transport.shutdown_conn(conn).await.unwrap();
+
+        assert_eq!(client.await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn resumes_handshake_after_cancelled_read() {
+        let cert = SelfSignedCert::generate(["localhost".to_string()]).unwrap();
+        let config = TlsConfig::new()
+            .cert_pem(cert.cert_pem.as_bytes(), cert.key_pem.as_bytes())
+            .unwrap();
+        let mut transport = TlsTransport::new(TcpTransport::new(), config).unwrap();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let addr = transport.inner().local_addr().unwrap();
+
+        let (hello_tx, hello_rx) = tokio::sync::oneshot::channel::<()>();
+        let client = tokio::spawn(async move {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            // Only start the handshake once the server's first read has been cancelled
+            hello_rx.await.unwrap();
+            let mut stream = handshake(tcp, "localhost", &[&cert], None).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            stream
+        });
+
+        let mut conn = transport.accept().await.unwrap();
+        let read = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            transport.read(&mut conn),
+        );
+        assert!(read.await.is_err());
+
+        hello_tx.send(()).unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn exposes_verified_client_certificate() {
+        let server = SelfSignedCert::generate(["localhost".to_string()]).unwrap();
+        let (ca_pem, client_cert) = client_ca_and_cert();
+        let client_der = client_cert.0[0].to_vec();
+
+        let transport = mtls_transport(&server, &ca_pem, false).await;
+        let addr = transport.inner().local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = try_connect(addr, "localhost", &[&server], Some(client_cert))
+                .await
+                .unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            stream
+        });
+
+        let mut conn = transport.accept().await.unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+
+        let extensions = transport.extensions(&conn);
+        let cert = extensions.get::<PeerCertificate>().unwrap();
+        assert_eq!(cert.subject, "CN=client");
+        assert_eq!(
+            cert.subject_alt_names,
+            [
+                SubjectAltName::Dns("client.test".to_string()),
+                SubjectAltName::Ip([127, 0, 0, 1].into()),
+            ]
+        );
+        let digest = ::ring::digest::digest(&::ring::digest::SHA256, &client_der);
+        let fingerprint: String = digest.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(cert.fingerprint, fingerprint);
+        assert_eq!(cert.der, client_der);
+
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_client_without_certificate() {
+        let server = SelfSignedCert::generate(["localhost".to_string()]).unwrap();
+        let (ca_pem, _) = client_ca_and_cert();
+
+        let transport = mtls_transport(&server, &ca_pem, false).await;
+        let addr = transport.inner().local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            // With TLS 1.3 the client may think the handshake succeeded
+            if let Ok(mut stream) = try_connect(addr, "localhost", &[&server], None).await {
+                let _ = stream.write_all(b"ping").await;
+            }
+        });
+
+        let mut conn = transport.accept().await.unwrap();
+        assert!(transport.read(&mut conn).await.is_err());
+        assert!(transport
+            .extensions(&conn)
+            .get::<PeerCertificate>()
+            .is_none());
+
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn optional_client_auth_accepts_client_without_certificate() {
+        let server = SelfSignedCert::generate(["localhost".to_string()]).unwrap();
+        let (ca_pem, _) = client_ca_and_cert();
+
+        let transport = mtls_transport(&server, &ca_pem, true).await;
+        let addr = transport.inner().local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = connect(addr, "localhost", &[&server]).await;
+            stream.write_all(b"ping").await.unwrap();
+            stream
+        });
+
+        let mut conn = transport.accept().await.unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+        assert!(transport
+            .extensions(&conn)
+            .get::<PeerCertificate>()
+            .is_none());
+
+        drop(client.await.unwrap());
+    }
+}
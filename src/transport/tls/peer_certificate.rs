@@ -0,0 +1,79 @@
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// A client certificate that was verified during the TLS handshake, attached to every request as
+/// an extension when client authentication is enabled, see
+/// [`TlsConfig::client_ca_pem`][super::TlsConfig::client_ca_pem]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    /// Distinguished name of the subject, e.g. `CN=client,O=Example`
+    pub subject: String,
+    pub subject_alt_names: Vec<SubjectAltName>,
+    /// Lowercase hex encoded SHA-256 digest of the DER encoded certificate
+    pub fingerprint: String,
+    /// DER encoded certificate
+    pub der: Vec<u8>,
+}
+
+/// A subject alternative name (SAN) of a [`PeerCertificate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+    /// Any other kind of name, as displayed by [x509_parser]
+    Other(String),
+}
+
+impl PeerCertificate {
+    /// Returns `None` if the certificate can't be parsed, which shouldn't happen for a certificate
+    /// that has already been verified
+    pub(crate) fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+        let subject_alt_names = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|san| {
+                san.value
+                    .general_names
+                    .iter()
+                    .map(SubjectAltName::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, der);
+        let fingerprint = digest.as_ref().iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            subject_alt_names,
+            fingerprint,
+            der: der.to_vec(),
+        })
+    }
+}
+
+impl From<&GeneralName<'_>> for SubjectAltName {
+    fn from(name: &GeneralName<'_>) -> Self {
+        match name {
+            GeneralName::DNSName(dns) => Self::Dns(dns.to_string()),
+            GeneralName::RFC822Name(email) => Self::Email(email.to_string()),
+            GeneralName::URI(uri) => Self::Uri(uri.to_string()),
+            GeneralName::IPAddress(&[a, b, c, d]) => Self::Ip(IpAddr::from([a, b, c, d])),
+            GeneralName::IPAddress(bytes) => match <[u8; 16]>::try_from(*bytes) {
+                Ok(octets) => Self::Ip(IpAddr::from(octets)),
+                Err(_) => Self::Other(name.to_string()),
+            },
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
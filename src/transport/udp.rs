@@ -0,0 +1,200 @@
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+
+#[cfg(unix)]
+use socket2::Type;
+
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+#[cfg(unix)]
+use super::{listen_fds, socket_addrs::bind_socket};
+use super::{SocketAddrs, Transport, TransportResult};
+use crate::{constants::MAX_DATAGRAM_SIZE, TransportError};
+
+/// Implementation of the transport layer for UDP datagrams
+///
+/// UDP is connection-less, so every received datagram is treated as its own "connection". The
+/// datagram carries the address of the peer that sent it, so the response is sent back to the
+/// right client.
+#[derive(Default)]
+pub struct UdpTransport {
+    socket: Option<UdpSocket>,
+    #[cfg(unix)]
+    reuse_port: bool,
+}
+
+/// A single datagram received by [`UdpTransport`], along with the peer that sent it
+#[derive(Debug)]
+pub struct UdpDatagram {
+    peer: SocketAddr,
+    payload: Option<Vec<u8>>,
+}
+
+impl UdpDatagram {
+    /// The address of the peer that sent this datagram, which is where the response is sent to
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl UdpTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The local address that the transport is bound to
+    pub fn local_addr(&self) -> TransportResult<SocketAddr> {
+        Ok(self.socket()?.local_addr()?)
+    }
+
+    /// Bind with `SO_REUSEPORT`, so that multiple processes can receive datagrams on the same
+    /// address and the kernel balances them between them, see
+    /// [`YarsServer::workers`][crate::YarsServer::workers]
+    #[cfg(unix)]
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    async fn bind_new(&self, addrs: &[SocketAddr]) -> std::io::Result<UdpSocket> {
+        #[cfg(unix)]
+        if self.reuse_port {
+            let socket = bind_socket(addrs, Type::DGRAM, |socket, _| {
+                socket.set_reuse_address(true)?;
+                socket.set_reuse_port(true)
+            })?;
+            return UdpSocket::from_std(socket.into());
+        }
+        UdpSocket::bind(addrs).await
+    }
+
+    fn socket(&self) -> TransportResult<&UdpSocket> {
+        // Error should never happen because this should only be used internally
+        self.socket.as_ref().ok_or(TransportError::Udp(
+            "UDP socket not bound. Call `bind` first.".into(),
+        ))
+    }
+}
+
+/// Receives datagrams on an inherited socket
+#[cfg(unix)]
+fn adopt(fd: OwnedFd) -> std::io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::from(fd);
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+impl Transport for UdpTransport {
+    type Addr = SocketAddrs;
+
+    type Connection = UdpDatagram;
+
+    async fn bind(&mut self, local_addr: SocketAddrs) -> TransportResult<()> {
+        let socket = match local_addr {
+            #[cfg(unix)]
+            SocketAddrs::Systemd(listen_fd) => adopt(listen_fd.take()?)?,
+            local_addr => {
+                let addrs = local_addr.resolve().await?;
+                // Already bound by systemd, or by the previous process on a hot restart
+                #[cfg(unix)]
+                if let Some(fd) = listen_fds::take_socket_bound_to(Type::DGRAM, &addrs) {
+                    adopt(fd)?
+                } else {
+                    self.bind_new(&addrs).await?
+                }
+                #[cfg(not(unix))]
+                self.bind_new(&addrs).await?
+            }
+        };
+        info!("Listening for UDP datagrams on {}", socket.local_addr()?);
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let (len, peer) = self.socket()?.recv_from(&mut buf).await?;
+        buf.truncate(len);
+
+        debug!(%peer, len, "Received UDP datagram");
+
+        Ok(UdpDatagram {
+            peer,
+            payload: Some(buf),
+        })
+    }
+
+    async fn read(&self, datagram: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        // A datagram can only be read once, subsequent reads act like a closed connection
+        Ok(datagram.payload.take().unwrap_or_default())
+    }
+
+    async fn write(&self, datagram: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        debug!(
+            peer = %datagram.peer,
+            len = response.len(),
+            "Writing UDP datagram",
+        );
+        self.socket()?.send_to(response, datagram.peer).await?;
+        Ok(())
+    }
+
+    async fn shutdown_conn(&self, _datagram: Self::Connection) -> TransportResult<()> {
+        // Nothing to shut down, the socket is shared by all peers
+        Ok(())
+    }
+
+    fn peer_addr(&self, datagram: &Self::Connection) -> Option<SocketAddr> {
+        Some(datagram.peer)
+    }
+
+    fn conn_local_addr(&self, _datagram: &Self::Connection) -> Option<SocketAddr> {
+        self.local_addr().ok()
+    }
+
+    fn kind(&self, _datagram: &Self::Connection) -> &'static str {
+        "udp"
+    }
+
+    #[cfg(unix)]
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        self.socket.iter().map(AsFd::as_fd).collect()
+    }
+
+    #[cfg(unix)]
+    fn set_reuse_port(&mut self, reuse_port: bool) -> TransportResult<()> {
+        self.reuse_port = reuse_port;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replies_to_sender_of_datagram() {
+        let mut transport = UdpTransport::new();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let server_addr = transport.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", server_addr).await.unwrap();
+
+        let mut datagram = transport.accept().await.unwrap();
+        assert_eq!(datagram.peer_addr(), client.local_addr().unwrap());
+        assert_eq!(transport.read(&mut datagram).await.unwrap(), b"ping");
+        // Datagram has already been consumed
+        assert!(transport.read(&mut datagram).await.unwrap().is_empty());
+
+        transport.write(&mut datagram, b"pong").await.unwrap();
+        transport.shutdown_conn(datagram).await.unwrap();
+
+        let mut buf = [0; 16];
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, server_addr);
+    }
+}
@@ -0,0 +1,333 @@
+use std::fmt;
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+use tracing::{debug, info, warn};
+
+use super::{listen_fds, ListenFd, Transport, TransportResult};
+use crate::{constants::MAX_REQUEST_SIZE, Extensions, TransportError};
+
+/// Implementation of the transport layer for Unix domain socket connections
+///
+/// A stale socket file left over from a previous run is removed when binding, and the socket
+/// file is removed again when the transport is shut down.
+///
+/// The credentials of the connected peer are attached to every request as a [`PeerCred`]
+/// extension.
+#[derive(Default)]
+pub struct UnixTransport {
+    listener: Option<UnixListener>,
+    /// Filesystem path of the socket this transport created, to be unlinked on shutdown
+    path: Option<PathBuf>,
+}
+
+/// Address of a Unix domain socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnixAddr {
+    /// A socket file on the filesystem
+    Path(PathBuf),
+    /// A socket in the Linux abstract namespace, which has no corresponding file
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Abstract(Vec<u8>),
+    /// An already bound socket passed by systemd socket activation
+    Systemd(ListenFd),
+}
+
+/// Credentials of the process on the other end of a Unix domain socket (`SO_PEERCRED`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    /// Not available on all platforms
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl UnixAddr {
+    /// An address in the Linux abstract namespace, `name` should not include the leading nul byte
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn abstract_name(name: impl AsRef<[u8]>) -> Self {
+        Self::Abstract(name.as_ref().to_vec())
+    }
+}
+
+impl fmt::Display for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.display()),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Self::Abstract(name) => write!(f, "@{}", String::from_utf8_lossy(name)),
+            Self::Systemd(listen_fd) => write!(f, "systemd socket {listen_fd}"),
+        }
+    }
+}
+
+impl From<&str> for UnixAddr {
+    fn from(path: &str) -> Self {
+        Self::Path(path.into())
+    }
+}
+
+impl From<String> for UnixAddr {
+    fn from(path: String) -> Self {
+        Self::Path(path.into())
+    }
+}
+
+impl From<&Path> for UnixAddr {
+    fn from(path: &Path) -> Self {
+        Self::Path(path.into())
+    }
+}
+
+impl From<PathBuf> for UnixAddr {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl From<ListenFd> for UnixAddr {
+    fn from(listen_fd: ListenFd) -> Self {
+        Self::Systemd(listen_fd)
+    }
+}
+
+impl UnixTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn listener(&self) -> TransportResult<&UnixListener> {
+        // Error should never happen because this should only be used internally
+        self.listener.as_ref().ok_or(TransportError::Unix(
+            "Unix listener not bound. Call `bind` first.".into(),
+        ))
+    }
+}
+
+/// Removes the socket file at `path` if no one is listening on it anymore.
+///
+/// Refuses to remove anything that isn't a socket, or a socket that is still in use.
+async fn remove_stale_socket(path: &Path) -> TransportResult<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(TransportError::Unix(format!(
+            "{} already exists and is not a socket",
+            path.display()
+        )));
+    }
+
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(TransportError::Io(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is already in use", path.display()),
+        ))),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!(path = %path.display(), "Removing stale Unix socket");
+            tokio::fs::remove_file(path).await?;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Listens on an inherited socket
+fn adopt(fd: OwnedFd) -> io::Result<UnixListener> {
+    let listener = std::os::unix::net::UnixListener::from(fd);
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+impl Transport for UnixTransport {
+    type Addr = UnixAddr;
+
+    type Connection = UnixStream;
+
+    async fn bind(&mut self, local_addr: UnixAddr) -> TransportResult<()> {
+        let listener = match &local_addr {
+            UnixAddr::Path(path) => {
+                // Already bound by systemd, or by the previous process on a hot restart. The
+                // socket file belongs to them, so it isn't removed on shutdown
+                match listen_fds::take_unix_bound_to(path) {
+                    Some(fd) => adopt(fd)?,
+                    None => {
+                        remove_stale_socket(path).await?;
+                        let listener = UnixListener::bind(path)?;
+                        self.path = Some(path.clone());
+                        listener
+                    }
+                }
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            UnixAddr::Abstract(name) => match listen_fds::take_unix_abstract(name) {
+                Some(fd) => adopt(fd)?,
+                None => {
+                    use std::os::linux::net::SocketAddrExt;
+
+                    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+                    listener.set_nonblocking(true)?;
+                    UnixListener::from_std(listener)?
+                }
+            },
+            // The socket file belongs to systemd, so it isn't removed on shutdown
+            UnixAddr::Systemd(listen_fd) => adopt(listen_fd.take()?)?,
+        };
+
+        info!("Listening for Unix socket connections on {local_addr}");
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let (stream, _addr) = self.listener()?.accept().await?;
+        match stream.peer_cred() {
+            Ok(cred) => debug!(
+                pid = cred.pid(),
+                uid = cred.uid(),
+                gid = cred.gid(),
+                "Accepted Unix socket connection"
+            ),
+            Err(_) => debug!("Accepted Unix socket connection"),
+        }
+        Ok(stream)
+    }
+
+    async fn read(&self, stream: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        let mut buf = Vec::with_capacity(MAX_REQUEST_SIZE);
+        let len = stream.read_buf(&mut buf).await?;
+
+        debug!(len, "Successfully read from Unix socket connection");
+
+        Ok(buf)
+    }
+
+    async fn write(&self, stream: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        debug!(len = response.len(), "Writing to Unix socket connection");
+        stream.write_all(response).await.map_err(|err| err.into())
+    }
+
+    async fn shutdown_conn(&self, mut stream: Self::Connection) -> TransportResult<()> {
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn kind(&self, _stream: &Self::Connection) -> &'static str {
+        "unix"
+    }
+
+    fn extensions(&self, stream: &Self::Connection) -> Extensions {
+        let mut extensions = Extensions::new();
+        if let Ok(cred) = stream.peer_cred() {
+            extensions.insert(PeerCred {
+                pid: cred.pid(),
+                uid: cred.uid(),
+                gid: cred.gid(),
+            });
+        }
+        extensions
+    }
+
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        self.listener.iter().map(AsFd::as_fd).collect()
+    }
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        if let Some(path) = &self.path {
+            debug!(path = %path.display(), "Removing Unix socket");
+            match tokio::fs::remove_file(path).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("yars-{}-{name}.sock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn exposes_peer_credentials() {
+        let path = socket_path("peer-cred");
+        let mut transport = UnixTransport::new();
+        transport.bind(path.clone().into()).await.unwrap();
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let mut conn = transport.accept().await.unwrap();
+
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+
+        let cred = client.peer_cred().unwrap();
+        let extensions = transport.extensions(&conn);
+        let peer = extensions.get::<PeerCred>().unwrap();
+        assert_eq!(peer.pid, Some(std::process::id() as i32));
+        assert_eq!(peer.uid, cred.uid());
+        assert_eq!(peer.gid, cred.gid());
+
+        transport.shutdown().await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_socket_file() {
+        let path = socket_path("stale");
+        // Dropping the listener leaves the socket file behind
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let mut transport = UnixTransport::new();
+        transport.bind(path.clone().into()).await.unwrap();
+        transport.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_to_replace_socket_in_use() {
+        let path = socket_path("in-use");
+        let mut first = UnixTransport::new();
+        first.bind(path.clone().into()).await.unwrap();
+
+        let mut second = UnixTransport::new();
+        assert!(second.bind(path.clone().into()).await.is_err());
+
+        first.shutdown().await.unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn binds_to_abstract_namespace() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("yars-{}-abstract", std::process::id());
+        let mut transport = UnixTransport::new();
+        transport
+            .bind(UnixAddr::abstract_name(&name))
+            .await
+            .unwrap();
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let client = std::os::unix::net::UnixStream::connect_addr(&addr).unwrap();
+        let conn = transport.accept().await.unwrap();
+        assert!(transport.extensions(&conn).get::<PeerCred>().is_some());
+
+        drop(client);
+        transport.shutdown().await.unwrap();
+    }
+}
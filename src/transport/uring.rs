@@ -0,0 +1,862 @@
+//! TCP transport built on io_uring, for comparing against [`TcpTransport`][super::TcpTransport]
+//! under high connection counts.
+//!
+//! The ring is owned by a dedicated driver thread, as io_uring submissions aren't tied to the
+//! tokio reactor. The transport sends it operations over a channel and wakes it with an eventfd,
+//! and the driver replies over a oneshot channel once each operation has completed. This keeps
+//! the transport [`Send`] and [`Sync`], so it works with the multi-threaded runtime like any
+//! other transport.
+//!
+//! Connections are accepted with a single multishot accept, which is cancelled while the server
+//! is behind on taking them and armed again once it catches up. Reads go into buffers registered
+//! with the ring. As the driver owns those buffers for as long as the kernel may write to them,
+//! a read can return an owned [`Vec<u8>`] like other transports, at the cost of copying out the
+//! bytes that were read.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering::{AcqRel, Acquire},
+};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread;
+
+use io_uring::{cqueue, opcode, squeue, types::Fd, IoUring};
+use socket2::{SockRef, Type};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::{debug, error, info, warn};
+
+use super::{listen_fds, socket_addrs::bind_socket, SocketAddrs, Transport, TransportResult};
+use crate::{
+    constants::{ACCEPT_QUEUE_LEN, MAX_REQUEST_SIZE},
+    TransportError,
+};
+
+/// Number of submission queue entries
+const RING_ENTRIES: u32 = 256;
+/// Size of each registered buffer, and so the most that a single read returns
+const BUFFER_SIZE: usize = MAX_REQUEST_SIZE;
+/// Default number of registered buffers, and so concurrent reads that don't need to allocate
+const REGISTERED_BUFFERS: u16 = 64;
+/// Default maximum number of pending connections, the same as [`TcpTransport`][super::TcpTransport]
+const BACKLOG: u32 = 1024;
+
+/// `user_data` of the multishot accept
+const ACCEPT: u64 = 0;
+/// `user_data` of the read on the eventfd that wakes the driver
+const WAKE: u64 = 1;
+/// `user_data` of cancellations, whose completions are ignored
+const CANCEL: u64 = 2;
+/// `user_data` of the first read or write
+const FIRST_OP: u64 = 3;
+
+/// Implementation of the transport layer for TCP connections, using io_uring instead of epoll.
+///
+/// Requires Linux 5.19 or later, for multishot accept. Only available with the `io-uring`
+/// feature.
+///
+/// Registered buffers count towards `RLIMIT_MEMLOCK`. If they can't be registered, a warning is
+/// logged and reads use ordinary buffers instead.
+pub struct UringTransport {
+    registered_buffers: u16,
+    backlog: u32,
+    reuse_port: bool,
+    bound: Option<Bound>,
+}
+
+impl Default for UringTransport {
+    fn default() -> Self {
+        Self {
+            registered_buffers: REGISTERED_BUFFERS,
+            backlog: BACKLOG,
+            reuse_port: false,
+            bound: None,
+        }
+    }
+}
+
+/// A TCP connection accepted by [`UringTransport`]
+#[derive(Debug)]
+pub struct UringConnection {
+    /// Shared with any operations in flight, so that the file descriptor isn't closed and reused
+    /// before the kernel has finished with it
+    fd: Arc<OwnedFd>,
+    peer: Option<SocketAddr>,
+    /// The result of a read in flight, kept until it arrives so that cancelling the read doesn't
+    /// lose the bytes the kernel reads
+    read: Option<oneshot::Receiver<io::Result<Vec<u8>>>>,
+}
+
+impl UringConnection {
+    /// Address of the client
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+/// State of a bound transport
+struct Bound {
+    listener: Arc<OwnedFd>,
+    driver: DriverHandle,
+    accepted: Mutex<mpsc::UnboundedReceiver<io::Result<OwnedFd>>>,
+    /// Number of connections in `accepted`, shared with the driver
+    queued: Arc<AtomicUsize>,
+}
+
+impl Drop for Bound {
+    fn drop(&mut self) {
+        self.driver.send(Op::Shutdown);
+    }
+}
+
+/// Sends operations to the driver thread
+struct DriverHandle {
+    ops: std_mpsc::Sender<Op>,
+    wake: Arc<OwnedFd>,
+}
+
+impl DriverHandle {
+    /// Sends `op` to the driver, returning `false` if it has stopped
+    fn send(&self, op: Op) -> bool {
+        if self.ops.send(op).is_err() {
+            return false;
+        }
+        let value: u64 = 1;
+        // SAFETY: writes the 8 bytes of `value` to the eventfd
+        unsafe {
+            libc::write(
+                self.wake.as_raw_fd(),
+                (&value as *const u64).cast(),
+                size_of::<u64>(),
+            )
+        };
+        true
+    }
+
+    /// Sends the operation built by `op` to the driver, and waits for its result
+    async fn run<T>(
+        &self,
+        op: impl FnOnce(oneshot::Sender<io::Result<T>>) -> Op,
+    ) -> TransportResult<T> {
+        let result = self.submit(op)?;
+        Ok(result.await.map_err(|_| TransportError::Closed)??)
+    }
+
+    /// Sends the operation built by `op` to the driver, returning where its result will arrive
+    fn submit<T>(
+        &self,
+        op: impl FnOnce(oneshot::Sender<io::Result<T>>) -> Op,
+    ) -> TransportResult<oneshot::Receiver<io::Result<T>>> {
+        let (reply, result) = oneshot::channel();
+        if !self.send(op(reply)) {
+            return Err(TransportError::Closed);
+        }
+        Ok(result)
+    }
+}
+
+/// An operation for the driver thread to carry out
+enum Op {
+    Read {
+        fd: Arc<OwnedFd>,
+        reply: oneshot::Sender<io::Result<Vec<u8>>>,
+    },
+    Write {
+        fd: Arc<OwnedFd>,
+        data: Vec<u8>,
+        reply: oneshot::Sender<io::Result<usize>>,
+    },
+    /// The server has caught up on accepted connections, so accepting can continue
+    ResumeAccept,
+    Shutdown,
+}
+
+impl UringTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of buffers to register with the ring, 64 by default. Reads beyond this many at
+    /// once allocate their own buffer.
+    pub fn registered_buffers(mut self, count: u16) -> Self {
+        self.registered_buffers = count;
+        self
+    }
+
+    /// Maximum number of pending connections waiting to be accepted, 1024 by default
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Bind with `SO_REUSEPORT`, so that multiple processes can listen on the same address and
+    /// the kernel balances connections between them, see
+    /// [`YarsServer::workers`][crate::YarsServer::workers]
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// The local address that the transport is bound to
+    pub fn local_addr(&self) -> TransportResult<SocketAddr> {
+        let listener = SockRef::from(&*self.bound()?.listener);
+        listener
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| TransportError::Uring("Listener isn't bound to an IP address".into()))
+    }
+
+    fn bind_new(&self, addrs: &[SocketAddr]) -> io::Result<OwnedFd> {
+        let socket = bind_socket(addrs, Type::STREAM, |socket, _| {
+            socket.set_reuse_address(true)?;
+            if self.reuse_port {
+                socket.set_reuse_port(true)?;
+            }
+            Ok(())
+        })?;
+        socket.listen(self.backlog.try_into().unwrap_or(i32::MAX))?;
+        Ok(socket.into())
+    }
+
+    fn bound(&self) -> TransportResult<&Bound> {
+        // Error should never happen because this should only be used internally
+        self.bound.as_ref().ok_or(TransportError::Uring(
+            "io_uring listener not bound. Call `bind` first.".into(),
+        ))
+    }
+}
+
+impl Transport for UringTransport {
+    type Addr = SocketAddrs;
+
+    type Connection = UringConnection;
+
+    async fn bind(&mut self, local_addr: SocketAddrs) -> TransportResult<()> {
+        let listener = match local_addr {
+            SocketAddrs::Systemd(listen_fd) => listen_fd.take()?,
+            local_addr => {
+                let addrs = local_addr.resolve().await?;
+                // Already bound by systemd, or by the previous process on a hot restart
+                match listen_fds::take_socket_bound_to(Type::STREAM, &addrs) {
+                    Some(fd) => fd,
+                    None => self.bind_new(&addrs)?,
+                }
+            }
+        };
+        let listener = Arc::new(listener);
+
+        let (accepted_tx, accepted) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let (driver, handle) = Driver::new(
+            listener.clone(),
+            accepted_tx,
+            queued.clone(),
+            self.registered_buffers,
+        )?;
+        thread::Builder::new()
+            .name("yars-io-uring".into())
+            .spawn(move || driver.run())?;
+
+        self.bound = Some(Bound {
+            listener,
+            driver: handle,
+            accepted: Mutex::new(accepted),
+            queued,
+        });
+        info!(
+            "Listening for TCP connections with io_uring on {}",
+            self.local_addr()?
+        );
+        Ok(())
+    }
+
+    async fn accept(&self) -> TransportResult<Self::Connection> {
+        let bound = self.bound()?;
+        let fd = match bound.accepted.lock().await.recv().await {
+            Some(fd) => fd,
+            None => return Err(TransportError::Closed),
+        };
+        // The driver stops accepting once the queue is full
+        if bound.queued.fetch_sub(1, AcqRel) == ACCEPT_QUEUE_LEN {
+            bound.driver.send(Op::ResumeAccept);
+        }
+        let fd = fd?;
+        let peer = SockRef::from(&fd)
+            .peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_socket());
+        debug!(addr = ?peer, "Accepted TCP connection with io_uring");
+        Ok(UringConnection {
+            fd: Arc::new(fd),
+            peer,
+            read: None,
+        })
+    }
+
+    async fn read(&self, conn: &mut Self::Connection) -> TransportResult<Vec<u8>> {
+        let result = match &mut conn.read {
+            Some(result) => result,
+            None => {
+                let fd = conn.fd.clone();
+                let result = self
+                    .bound()?
+                    .driver
+                    .submit(|reply| Op::Read { fd, reply })?;
+                conn.read.insert(result)
+            }
+        };
+        let result = result.await;
+        conn.read = None;
+        let buf = result.map_err(|_| TransportError::Closed)??;
+        debug!(peer = ?conn.peer, len = buf.len(), "Successfully read with io_uring");
+        Ok(buf)
+    }
+
+    async fn write(&self, conn: &mut Self::Connection, response: &[u8]) -> TransportResult<()> {
+        debug!(peer = ?conn.peer, len = response.len(), "Writing with io_uring");
+        let driver = &self.bound()?.driver;
+        let mut remaining = response;
+        while !remaining.is_empty() {
+            let fd = conn.fd.clone();
+            let data = remaining.to_vec();
+            let written = driver.run(|reply| Op::Write { fd, data, reply }).await?;
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            remaining = &remaining[written..];
+        }
+        Ok(())
+    }
+
+    async fn shutdown_conn(&self, conn: Self::Connection) -> TransportResult<()> {
+        let socket = SockRef::from(&*conn.fd);
+        match conn.read {
+            // A read left in flight would keep its buffer and the file descriptor until the
+            // client sends something, so end it and wait for it to complete
+            Some(read) => {
+                socket.shutdown(Shutdown::Both)?;
+                let _ = read.await;
+            }
+            None => socket.shutdown(Shutdown::Write)?,
+        }
+        Ok(())
+    }
+
+    fn peer_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        conn.peer
+    }
+
+    fn conn_local_addr(&self, conn: &Self::Connection) -> Option<SocketAddr> {
+        SockRef::from(&*conn.fd).local_addr().ok()?.as_socket()
+    }
+
+    fn kind(&self, _conn: &Self::Connection) -> &'static str {
+        "tcp"
+    }
+
+    fn listeners(&self) -> Vec<BorrowedFd<'_>> {
+        self.bound
+            .iter()
+            .map(|bound| bound.listener.as_fd())
+            .collect()
+    }
+
+    fn set_reuse_port(&mut self, reuse_port: bool) -> TransportResult<()> {
+        self.reuse_port = reuse_port;
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> TransportResult<()> {
+        // Stops accepting, after which `accept` returns `Closed`
+        if let Some(bound) = &self.bound {
+            bound.driver.send(Op::Shutdown);
+        }
+        Ok(())
+    }
+}
+
+/// An operation submitted to the ring, along with everything the kernel may still be using
+enum InFlight {
+    Read {
+        _fd: Arc<OwnedFd>,
+        buffer: ReadBuffer,
+        reply: oneshot::Sender<io::Result<Vec<u8>>>,
+    },
+    Write {
+        _fd: Arc<OwnedFd>,
+        _data: Vec<u8>,
+        reply: oneshot::Sender<io::Result<usize>>,
+    },
+}
+
+enum ReadBuffer {
+    /// Index of a registered buffer
+    Registered(u16),
+    /// Allocated because no registered buffers were free
+    Owned(Vec<u8>),
+}
+
+/// Owns the ring, and everything the kernel may write to, on the driver thread
+struct Driver {
+    // Dropped first, before the buffers it may have been using
+    ring: IoUring,
+    listener: Arc<OwnedFd>,
+    accepted: mpsc::UnboundedSender<io::Result<OwnedFd>>,
+    /// Number of connections sent to the transport that it hasn't taken yet
+    queued: Arc<AtomicUsize>,
+    ops: std_mpsc::Receiver<Op>,
+    wake: Arc<OwnedFd>,
+    wake_buf: Box<u64>,
+    buffers: Vec<Box<[u8]>>,
+    free_buffers: Vec<u16>,
+    in_flight: HashMap<u64, InFlight>,
+    next_id: u64,
+    accepting: bool,
+    /// The accept has been cancelled because the queue is full
+    pausing_accept: bool,
+    waiting_for_wake: bool,
+    shutting_down: bool,
+}
+
+impl Driver {
+    fn new(
+        listener: Arc<OwnedFd>,
+        accepted: mpsc::UnboundedSender<io::Result<OwnedFd>>,
+        queued: Arc<AtomicUsize>,
+        registered_buffers: u16,
+    ) -> io::Result<(Self, DriverHandle)> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+
+        // SAFETY: `eventfd` has no memory safety requirements
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `wake` was just created, so is owned by nothing else
+        let wake = Arc::new(unsafe { OwnedFd::from_raw_fd(wake) });
+
+        let mut buffers: Vec<Box<[u8]>> = (0..registered_buffers)
+            .map(|_| vec![0; BUFFER_SIZE].into_boxed_slice())
+            .collect();
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        // SAFETY: the buffers are owned by the driver, so outlive the ring
+        let free_buffers = match unsafe { ring.submitter().register_buffers(&iovecs) } {
+            Ok(()) => (0..registered_buffers).rev().collect(),
+            Err(err) if !buffers.is_empty() => {
+                warn!(
+                    ?err,
+                    "Couldn't register io_uring buffers, reads will allocate"
+                );
+                buffers.clear();
+                Vec::new()
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let (ops_tx, ops) = std_mpsc::channel();
+        let handle = DriverHandle {
+            ops: ops_tx,
+            wake: wake.clone(),
+        };
+        let driver = Self {
+            ring,
+            listener,
+            accepted,
+            queued,
+            ops,
+            wake,
+            wake_buf: Box::new(0),
+            buffers,
+            free_buffers,
+            in_flight: HashMap::new(),
+            next_id: FIRST_OP,
+            accepting: false,
+            pausing_accept: false,
+            waiting_for_wake: false,
+            shutting_down: false,
+        };
+        Ok((driver, handle))
+    }
+
+    fn run(mut self) {
+        match self.run_inner() {
+            Ok(()) => debug!("io_uring driver stopped"),
+            Err(err) => {
+                error!(?err, "io_uring driver failed");
+                // The kernel may still write to buffers of operations in flight, so they must
+                // never be freed
+                std::mem::forget(self);
+            }
+        }
+    }
+
+    fn run_inner(&mut self) -> io::Result<()> {
+        self.arm_accept()?;
+        self.arm_wake()?;
+
+        while !self.is_finished() {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+            let completions: Vec<_> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+                .collect();
+            for (user_data, result, flags) in completions {
+                self.complete(user_data, result, flags)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.shutting_down && !self.accepting && !self.waiting_for_wake && self.in_flight.is_empty()
+    }
+
+    /// Pushes `entry` to the submission queue, submitting queued entries if it is full.
+    ///
+    /// # Safety
+    /// Any memory used by `entry` must stay valid until its completion has been received.
+    unsafe fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        while self.ring.submission().push(&entry).is_err() {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+
+    fn arm_accept(&mut self) -> io::Result<()> {
+        let entry = opcode::AcceptMulti::new(Fd(self.listener.as_raw_fd()))
+            .flags(libc::SOCK_CLOEXEC)
+            .build()
+            .user_data(ACCEPT);
+        // SAFETY: the listener is kept open by the driver
+        unsafe { self.push(entry)? };
+        self.accepting = true;
+        Ok(())
+    }
+
+    /// Cancels the accept, so that connections wait in the listen backlog until the transport
+    /// has caught up
+    fn pause_accept(&mut self) -> io::Result<()> {
+        if !self.accepting || self.pausing_accept {
+            return Ok(());
+        }
+        debug!("Accept queue is full, pausing accepting");
+        self.pausing_accept = true;
+        self.cancel(ACCEPT)
+    }
+
+    /// Arms the accept again, unless it is still armed or the queue is full
+    fn resume_accept(&mut self) -> io::Result<()> {
+        if self.accepting || self.shutting_down || self.queued.load(Acquire) >= ACCEPT_QUEUE_LEN {
+            return Ok(());
+        }
+        self.arm_accept()
+    }
+
+    fn cancel(&mut self, target: u64) -> io::Result<()> {
+        let entry = opcode::AsyncCancel::new(target).build().user_data(CANCEL);
+        // SAFETY: cancellations don't use any memory
+        unsafe { self.push(entry) }
+    }
+
+    fn arm_wake(&mut self) -> io::Result<()> {
+        let buf: *mut u64 = &mut *self.wake_buf;
+        let entry = opcode::Read::new(
+            Fd(self.wake.as_raw_fd()),
+            buf.cast(),
+            size_of::<u64>() as u32,
+        )
+        .build()
+        .user_data(WAKE);
+        // SAFETY: `wake_buf` is boxed, so stays at the same address until the driver is dropped
+        unsafe { self.push(entry)? };
+        self.waiting_for_wake = true;
+        Ok(())
+    }
+
+    fn complete(&mut self, user_data: u64, result: i32, flags: u32) -> io::Result<()> {
+        match user_data {
+            ACCEPT => {
+                let accepted = if result >= 0 {
+                    // SAFETY: the accepted socket is owned by nothing else
+                    Ok(unsafe { OwnedFd::from_raw_fd(result as RawFd) })
+                } else {
+                    Err(io::Error::from_raw_os_error(-result))
+                };
+                // Cancelled by pausing or shutting down
+                if result != -libc::ECANCELED {
+                    // Counted before sending, as the transport uncounts it once received
+                    let queued = self.queued.fetch_add(1, AcqRel) + 1;
+                    // Dropped (and closed) if the transport has been dropped
+                    let _ = self.accepted.send(accepted);
+                    if queued >= ACCEPT_QUEUE_LEN {
+                        self.pause_accept()?;
+                    }
+                }
+                if !cqueue::more(flags) {
+                    self.accepting = false;
+                    self.pausing_accept = false;
+                    self.resume_accept()?;
+                }
+            }
+            WAKE => {
+                self.waiting_for_wake = false;
+                self.receive_ops()?;
+                if !self.shutting_down {
+                    self.arm_wake()?;
+                }
+            }
+            CANCEL => {}
+            id => self.complete_op(id, result),
+        }
+        Ok(())
+    }
+
+    /// Submits all operations sent by the transport
+    fn receive_ops(&mut self) -> io::Result<()> {
+        loop {
+            match self.ops.try_recv() {
+                Ok(Op::Shutdown) | Err(std_mpsc::TryRecvError::Disconnected) => {
+                    return self.shut_down();
+                }
+                Ok(Op::ResumeAccept) => self.resume_accept()?,
+                Ok(op) => self.submit(op)?,
+                Err(std_mpsc::TryRecvError::Empty) => return Ok(()),
+            }
+        }
+    }
+
+    fn submit(&mut self, op: Op) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (entry, in_flight) = match op {
+            Op::Read { fd, reply } => {
+                let raw_fd = Fd(fd.as_raw_fd());
+                let (entry, buffer) = match self.free_buffers.pop() {
+                    Some(index) => {
+                        let buf = &mut self.buffers[usize::from(index)];
+                        let entry = opcode::ReadFixed::new(
+                            raw_fd,
+                            buf.as_mut_ptr(),
+                            buf.len() as u32,
+                            index,
+                        );
+                        (entry.build(), ReadBuffer::Registered(index))
+                    }
+                    None => {
+                        let mut buf = vec![0; BUFFER_SIZE];
+                        let entry = opcode::Read::new(raw_fd, buf.as_mut_ptr(), buf.len() as u32);
+                        (entry.build(), ReadBuffer::Owned(buf))
+                    }
+                };
+                let in_flight = InFlight::Read {
+                    _fd: fd,
+                    buffer,
+                    reply,
+                };
+                (entry, in_flight)
+            }
+            Op::Write { fd, data, reply } => {
+                let entry =
+                    opcode::Write::new(Fd(fd.as_raw_fd()), data.as_ptr(), data.len() as u32);
+                let in_flight = InFlight::Write {
+                    _fd: fd,
+                    _data: data,
+                    reply,
+                };
+                (entry.build(), in_flight)
+            }
+            Op::ResumeAccept => return self.resume_accept(),
+            Op::Shutdown => return self.shut_down(),
+        };
+
+        // SAFETY: the file descriptor and buffer are kept in `in_flight` until completion
+        unsafe { self.push(entry.user_data(id))? };
+        self.in_flight.insert(id, in_flight);
+        Ok(())
+    }
+
+    fn complete_op(&mut self, id: u64, result: i32) {
+        let result = if result >= 0 {
+            Ok(result as usize)
+        } else {
+            Err(io::Error::from_raw_os_error(-result))
+        };
+        match self.in_flight.remove(&id) {
+            Some(InFlight::Read {
+                _fd: fd,
+                buffer,
+                reply,
+            }) => {
+                // Let go of the file descriptor before replying, so that it is closed along with
+                // the connection
+                drop(fd);
+                let data = match buffer {
+                    ReadBuffer::Registered(index) => {
+                        let buf = &self.buffers[usize::from(index)];
+                        let data = result.map(|len| buf[..len].to_vec());
+                        self.free_buffers.push(index);
+                        data
+                    }
+                    ReadBuffer::Owned(mut buf) => result.map(|len| {
+                        buf.truncate(len);
+                        buf
+                    }),
+                };
+                let _ = reply.send(data);
+            }
+            Some(InFlight::Write { _fd: fd, reply, .. }) => {
+                drop(fd);
+                let _ = reply.send(result);
+            }
+            None => warn!(id, "Completion for unknown io_uring operation"),
+        }
+    }
+
+    /// Cancels everything in flight, after which the driver stops once their completions have
+    /// been received
+    fn shut_down(&mut self) -> io::Result<()> {
+        if self.shutting_down {
+            return Ok(());
+        }
+        self.shutting_down = true;
+
+        let mut targets: Vec<u64> = self.in_flight.keys().copied().collect();
+        if self.accepting {
+            targets.push(ACCEPT);
+        }
+        if self.waiting_for_wake {
+            targets.push(WAKE);
+        }
+        for target in targets {
+            self.cancel(target)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    async fn echo_once(transport: UringTransport) {
+        let mut transport = transport;
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let mut client = TcpStream::connect(transport.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut conn = transport.accept().await.unwrap();
+        assert_eq!(
+            transport.peer_addr(&conn),
+            Some(client.local_addr().unwrap())
+        );
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+        transport.write(&mut conn, b"pong").await.unwrap();
+        transport.shutdown_conn(conn).await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"pong");
+
+        transport.shutdown().await.unwrap();
+        assert!(matches!(
+            transport.accept().await,
+            Err(TransportError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_into_registered_buffers() {
+        echo_once(UringTransport::new()).await;
+    }
+
+    #[tokio::test]
+    async fn reads_without_registered_buffers() {
+        echo_once(UringTransport::new().registered_buffers(0)).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_read_in_flight_when_cancelled() {
+        let mut transport = UringTransport::new();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let mut client = TcpStream::connect(transport.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut conn = transport.accept().await.unwrap();
+
+        let read = tokio::time::timeout(Duration::from_millis(50), transport.read(&mut conn));
+        assert!(read.await.is_err());
+
+        // Completes the read that was cancelled, rather than one submitted after it
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(transport.read(&mut conn).await.unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn ends_read_in_flight_on_shutdown() {
+        let mut transport = UringTransport::new();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let _client = TcpStream::connect(transport.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut conn = transport.accept().await.unwrap();
+
+        let read = tokio::time::timeout(Duration::from_millis(50), transport.read(&mut conn));
+        assert!(read.await.is_err());
+
+        let fd = conn.fd.clone();
+        tokio::time::timeout(Duration::from_secs(5), transport.shutdown_conn(conn))
+            .await
+            .unwrap()
+            .unwrap();
+        // The driver has let go of the file descriptor, so it is closed along with ours
+        assert_eq!(Arc::strong_count(&fd), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_accepting_while_queue_is_full() {
+        let mut transport = UringTransport::new();
+        transport.bind("127.0.0.1:0".into()).await.unwrap();
+        let addr = transport.local_addr().unwrap();
+
+        let clients = 2 * ACCEPT_QUEUE_LEN;
+        let mut streams = Vec::new();
+        for _ in 0..clients {
+            streams.push(TcpStream::connect(addr).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // The rest wait in the listen backlog
+        let queued = transport.bound().unwrap().queued.load(Acquire);
+        assert!(queued < clients, "{queued} connections queued");
+
+        for _ in 0..clients {
+            tokio::time::timeout(Duration::from_secs(5), transport.accept())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
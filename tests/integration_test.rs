@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 use yars::{
     http::{HttpRequest, HttpResponse},
     protocol::HttpProtocol,
-    transport::TcpTransport,
+    transport::{MemoryTransport, TcpTransport, Transport},
     YarsServer,
 };
 
@@ -33,8 +33,8 @@ async fn text(_req: HttpRequest) -> Result<HttpResponse> {
     Ok(HttpResponse::Ok().header("a", "b").text("Hello there"))
 }
 
-fn test_server() -> YarsServer<TcpTransport, HttpProtocol> {
-    YarsServer::default_server()
+fn test_server<T: Transport>(transport: T) -> YarsServer<T, HttpProtocol> {
+    YarsServer::new(transport, HttpProtocol)
         .get("/json", json)
         .get("/text", text)
 }
@@ -43,7 +43,7 @@ fn test_server() -> YarsServer<TcpTransport, HttpProtocol> {
 async fn tcp_http_server() -> Result<()> {
     let url = "localhost:8000";
 
-    let server_future_handle = tokio::spawn(test_server().listen(url));
+    let server_future_handle = tokio::spawn(test_server(TcpTransport::new()).listen(url));
     // Wait for server to start
     tokio::time::sleep(Duration::from_millis(1_000)).await;
 
@@ -76,3 +76,27 @@ async fn tcp_http_server() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn memory_http_server() -> Result<()> {
+    let (transport, client) = MemoryTransport::new();
+    tokio::spawn(test_server(transport).listen(()));
+
+    // No need to wait for the server to start, connections are queued until it accepts them
+    let text_response = client.send(b"GET /text HTTP/1.1\r\n\r\n").await?;
+    let text_response = String::from_utf8(text_response)?;
+    assert!(text_response.starts_with("HTTP/1.1 200 OK"));
+    assert!(text_response.contains("Content-Type: text/plain\r\n"));
+    assert!(text_response.contains("a: b\r\n"));
+    assert!(text_response.ends_with("\r\n\r\nHello there"));
+
+    let json_response = client.send(b"GET /json HTTP/1.1\r\n\r\n").await?;
+    let json_response = String::from_utf8(json_response)?;
+    assert!(json_response.contains("Content-Type: application/json\r\n"));
+    let (_headers, body) = json_response.split_once("\r\n\r\n").unwrap();
+    let user: User = serde_json::from_str(body)?;
+    assert_eq!(user.name, "John");
+    assert_eq!(user.age, 30);
+
+    Ok(())
+}